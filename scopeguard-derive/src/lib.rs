@@ -0,0 +1,179 @@
+//! Derive and attribute macros for the
+//! [`scopeguard`](https://docs.rs/scopeguard/) crate.
+//!
+//! Use them through the `derive` feature of `scopeguard`, which re-exports
+//! [`Finalize`](derive.Finalize.html) and [`deferred`](attr.deferred.html).
+
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::quote;
+use syn::parse::{Parse, ParseStream};
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Fields, Ident, Index, ItemFn, Token};
+
+/// Derive a `Drop` implementation that runs cleanup expressions for the
+/// annotated fields.
+///
+/// Annotate a field with `#[finalize(cleanup)]`, where `cleanup` is a
+/// closure or function that takes `&mut` the field. Add the strategy
+/// `on_unwind` or `on_success` before it, as in
+/// `#[finalize(on_unwind, cleanup)]`, to run it only when the value is dropped
+/// during unwinding or outside of it.
+///
+/// The cleanups run in reverse field order. Each one is a scope guard, so if
+/// one panics the remaining cleanups still run. The fields themselves are
+/// dropped afterwards, as usual.
+///
+/// ```ignore
+/// #[derive(Finalize)]
+/// struct Transaction {
+///     #[finalize(|conn: &mut Connection| conn.release())]
+///     conn: Connection,
+///     #[finalize(on_unwind, Journal::rollback)]
+///     journal: Journal,
+/// }
+/// ```
+#[proc_macro_derive(Finalize, attributes(finalize))]
+pub fn derive_finalize(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+/// Run an expression at every exit of the function: when it returns, and
+/// when it unwinds from a panic.
+///
+/// `#[deferred(expr)]` places a scope guard at the start of the function
+/// body. Add the strategy `on_unwind` or `on_success` before the expression,
+/// as in `#[deferred(on_unwind, expr)]`, to run it only when the function
+/// unwinds or only when it returns. The expression borrows the variables it
+/// uses, including the function's arguments, for the whole body.
+///
+/// With several `deferred` attributes, the expressions run in the order the
+/// attributes are written.
+///
+/// The attribute is not named `defer`: attribute macros and `macro_rules!`
+/// macros share a namespace, so `scopeguard::defer` could not name both it and
+/// the [`defer!`](https://docs.rs/scopeguard/*/scopeguard/macro.defer.html)
+/// macro.
+///
+/// ```ignore
+/// #[scopeguard::deferred(cleanup_tempfiles())]
+/// fn build() -> io::Result<()> {
+///     compile()?;
+///     link()
+/// }
+/// ```
+#[proc_macro_attribute]
+pub fn deferred(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let deferred = parse_macro_input!(attr as Finalizer);
+    let mut function = parse_macro_input!(item as ItemFn);
+    let constructor = constructor(deferred.strategy);
+    let cleanup = deferred.cleanup;
+    let guard = Ident::new("__scopeguard_deferred", Span::mixed_site());
+    let body = function.block.clone();
+    *function.block = syn::parse_quote!({
+        let #guard = #constructor((), |()| {
+            #cleanup;
+        });
+        #body
+    });
+    quote!(#function).into()
+}
+
+struct Finalizer {
+    strategy: Option<Ident>,
+    cleanup: Expr,
+}
+
+impl Parse for Finalizer {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let strategy = if input.peek(Ident) && input.peek2(Token![,]) {
+            let ident: Ident = input.parse()?;
+            if ident != "on_unwind" && ident != "on_success" {
+                return Err(syn::Error::new(
+                    ident.span(),
+                    "expected `on_unwind` or `on_success`",
+                ));
+            }
+            input.parse::<Token![,]>()?;
+            Some(ident)
+        } else {
+            None
+        };
+        Ok(Finalizer {
+            strategy,
+            cleanup: input.parse()?,
+        })
+    }
+}
+
+/// Return the path of the guard constructor for `strategy`.
+fn constructor(strategy: Option<Ident>) -> TokenStream2 {
+    match strategy {
+        None => quote!(::scopeguard::guard),
+        Some(ident) if ident == "on_unwind" => quote!(::scopeguard::guard_on_unwind),
+        Some(_) => quote!(::scopeguard::guard_on_success),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => &data.fields,
+        _ => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "Finalize can only be derived for structs",
+            ))
+        }
+    };
+    let fields = match fields {
+        Fields::Named(fields) => &fields.named,
+        Fields::Unnamed(fields) => &fields.unnamed,
+        Fields::Unit => {
+            return Err(syn::Error::new_spanned(
+                &input.ident,
+                "no fields to finalize",
+            ))
+        }
+    };
+
+    let mut guards = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let member = match &field.ident {
+            Some(ident) => quote!(#ident),
+            None => {
+                let index = Index::from(i);
+                quote!(#index)
+            }
+        };
+        for attr in field.attrs.iter().filter(|a| a.path().is_ident("finalize")) {
+            let Finalizer { strategy, cleanup } = attr.parse_args()?;
+            let constructor = constructor(strategy);
+            let guard = Ident::new(&format!("__finalize_{}", guards.len()), field_span(field));
+            guards.push(quote! {
+                let #guard = #constructor(&mut self.#member, #cleanup);
+            });
+        }
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics ::scopeguard::__private::Drop for #name #ty_generics #where_clause {
+            fn drop(&mut self) {
+                #(#guards)*
+            }
+        }
+    })
+}
+
+fn field_span(field: &syn::Field) -> proc_macro2::Span {
+    match &field.ident {
+        Some(ident) => ident.span(),
+        None => proc_macro2::Span::call_site(),
+    }
+}
@@ -0,0 +1,244 @@
+//! Guards that observe or fail the allocations made during a scope.
+//!
+//! The guards in this module rely on the [`Tracking`](struct.Tracking.html)
+//! allocator, which must be installed as the global allocator. Without it,
+//! no allocations are observed.
+//!
+//! Requires crate feature `allocator`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::ptr;
+
+use guard;
+use ScopeGuard;
+
+/// A global allocator that wraps another allocator, counts allocations per
+/// thread and can be made to fail them.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::alloc::System;
+/// use scopeguard::allocator::Tracking;
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Tracking<System> = Tracking::new(System);
+///
+/// fn main() {}
+/// ```
+#[derive(Debug, Default)]
+pub struct Tracking<A = System> {
+    inner: A,
+}
+
+impl<A> Tracking<A> {
+    /// Wrap the allocator `inner`.
+    pub const fn new(inner: A) -> Self {
+        Tracking { inner }
+    }
+}
+
+thread_local! {
+    static ALLOCATIONS: Cell<AllocStats> = const {
+        Cell::new(AllocStats {
+            allocations: 0,
+            bytes: 0,
+        })
+    };
+    // The number of allocations to let through before failing one.
+    static FAIL_AFTER: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Return `true` if the current allocation must fail.
+fn inject_failure() -> bool {
+    FAIL_AFTER
+        .try_with(|fail_after| match fail_after.get() {
+            Some(0) => {
+                fail_after.set(None);
+                true
+            }
+            Some(n) => {
+                fail_after.set(Some(n - 1));
+                false
+            }
+            None => false,
+        })
+        .unwrap_or(false)
+}
+
+fn record(size: usize) {
+    // Allocations made while thread-locals are destroyed are not counted.
+    let _ = ALLOCATIONS.try_with(|stats| {
+        let mut s = stats.get();
+        s.allocations += 1;
+        s.bytes += size as u64;
+        stats.set(s);
+    });
+}
+
+fn current() -> AllocStats {
+    ALLOCATIONS.try_with(Cell::get).unwrap_or_default()
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Tracking<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if inject_failure() {
+            return ptr::null_mut();
+        }
+        record(layout.size());
+        self.inner.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if inject_failure() {
+            return ptr::null_mut();
+        }
+        record(layout.size());
+        self.inner.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if inject_failure() {
+            return ptr::null_mut();
+        }
+        record(new_size);
+        self.inner.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout)
+    }
+}
+
+/// Allocation counts, as passed to the closure of
+/// [`track_allocations`](fn.track_allocations.html).
+///
+/// A reallocation counts as one allocation of its new size.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct AllocStats {
+    /// The number of allocations.
+    pub allocations: u64,
+    /// The total number of bytes requested.
+    pub bytes: u64,
+}
+
+/// Create a guard that counts the allocations made by the current thread
+/// until it is dropped, and passes the totals to `dropfn`.
+///
+/// The closure runs on scope exit, including during unwinding, so allocation
+/// budgets can be logged or asserted even for scopes that panic.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::alloc::System;
+/// use scopeguard::allocator::{track_allocations, Tracking};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Tracking<System> = Tracking::new(System);
+///
+/// fn main() {
+///     let _budget = track_allocations(|stats| {
+///         assert!(stats.allocations <= 1, "too many allocations: {:?}", stats);
+///     });
+///     let v: Vec<u8> = Vec::with_capacity(64);
+///     # drop(v);
+/// }
+/// ```
+pub fn track_allocations<F>(dropfn: F) -> ScopeGuard<AllocStats, impl FnOnce(AllocStats)>
+where
+    F: FnOnce(AllocStats),
+{
+    guard(current(), |start| {
+        let end = current();
+        dropfn(AllocStats {
+            allocations: end.allocations - start.allocations,
+            bytes: end.bytes - start.bytes,
+        })
+    })
+}
+
+/// Create a guard that makes the `n`th allocation of the current thread
+/// fail, counting from one, until it is dropped.
+///
+/// The failing allocation returns a null pointer, as if the system were out
+/// of memory. Fallible APIs like `Vec::try_reserve` report it as an error,
+/// while infallible ones call `std::alloc::handle_alloc_error`, which aborts
+/// the process. Use it to exercise the error and cleanup paths of code that
+/// handles allocation failure.
+///
+/// Only one allocation fails. When the guard is dropped, the previous
+/// setting is restored.
+///
+/// ***Panics*** if `n` is zero.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::alloc::{alloc, Layout, System};
+/// use scopeguard::allocator::{fail_nth_allocation, Tracking};
+///
+/// #[global_allocator]
+/// static ALLOCATOR: Tracking<System> = Tracking::new(System);
+///
+/// fn main() {
+///     let layout = Layout::new::<u64>();
+///     let _oom = fail_nth_allocation(1);
+///     let ptr = unsafe { alloc(layout) };
+///     assert!(ptr.is_null());
+/// }
+/// ```
+pub fn fail_nth_allocation(n: u64) -> ScopeGuard<Option<u64>, impl FnOnce(Option<u64>)> {
+    assert!(
+        n != 0,
+        "fail_nth_allocation: allocations are counted from one"
+    );
+    let previous = FAIL_AFTER.with(|fail_after| fail_after.replace(Some(n - 1)));
+    guard(previous, |previous| {
+        let _ = FAIL_AFTER.try_with(|fail_after| fail_after.set(previous));
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[global_allocator]
+    static ALLOCATOR: Tracking = Tracking::new(System);
+
+    #[test]
+    fn test_track_allocations() {
+        let stats = Cell::new(AllocStats::default());
+        {
+            let _g = track_allocations(|s| stats.set(s));
+            let v = vec![0u8; 100];
+            drop(v);
+        }
+        assert_eq!(stats.get().allocations, 1);
+        assert_eq!(stats.get().bytes, 100);
+    }
+
+    #[test]
+    fn test_fail_nth_allocation() {
+        use std::alloc::{alloc, dealloc};
+
+        let layout = Layout::new::<u64>();
+        unsafe {
+            let _oom = fail_nth_allocation(2);
+            let first = alloc(layout);
+            assert!(!first.is_null());
+            assert!(alloc(layout).is_null());
+            let third = alloc(layout);
+            assert!(!third.is_null());
+            dealloc(first, layout);
+            dealloc(third, layout);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "counted from one")]
+    fn test_fail_zeroth_allocation() {
+        let _oom = fail_nth_allocation(0);
+    }
+}
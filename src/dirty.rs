@@ -0,0 +1,168 @@
+//! Guards whose closure only runs if the value was modified.
+
+use std::fmt;
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+
+use Always;
+use ScopeGuard;
+use Strategy;
+
+/// A scope guard that tracks mutable access to its value, and only runs its
+/// closure if the value was accessed mutably.
+///
+/// Any use of `DerefMut` marks the value as dirty, even if it was not actually
+/// changed. Read-only access through `Deref` does not.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::Cell;
+/// use scopeguard::dirty::DirtyGuard;
+///
+/// fn main() {
+///     let saves = Cell::new(0);
+///     let save = |_config: Vec<String>| saves.set(saves.get() + 1);
+///     {
+///         let config = DirtyGuard::new(vec![String::from("theme=dark")], save);
+///         assert_eq!(config.len(), 1);
+///     }
+///     assert_eq!(saves.get(), 0);
+///     {
+///         let mut config = DirtyGuard::new(vec![String::from("theme=dark")], save);
+///         config.push(String::from("font=mono"));
+///     }
+///     assert_eq!(saves.get(), 1);
+/// }
+/// ```
+pub struct DirtyGuard<T, F, S = Always>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    guard: ScopeGuard<T, F, S>,
+    dirty: bool,
+}
+
+impl<T, F> DirtyGuard<T, F, Always>
+where
+    F: FnOnce(T),
+{
+    /// Create a guard that owns `v` and calls `dropfn` when dropped, if the
+    /// value was accessed mutably.
+    pub fn new(v: T, dropfn: F) -> Self {
+        DirtyGuard::from_guard(::guard(v, dropfn))
+    }
+}
+
+impl<T, F, S> DirtyGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    /// Track mutable access to the value of `guard`. The closure then runs
+    /// only if the value was accessed mutably and `guard` is armed and its
+    /// strategy allows it.
+    pub fn from_guard(guard: ScopeGuard<T, F, S>) -> Self {
+        DirtyGuard {
+            guard,
+            dirty: false,
+        }
+    }
+
+    /// Return `true` if the value was accessed mutably.
+    pub fn is_dirty(guard: &Self) -> bool {
+        guard.dirty
+    }
+
+    /// Mark the value as unmodified, for example after saving it.
+    pub fn mark_clean(guard: &mut Self) {
+        guard.dirty = false;
+    }
+
+    /// “Defuse” the guard and extract the value without calling the closure.
+    pub fn into_inner(guard: Self) -> T {
+        ScopeGuard::into_inner(DirtyGuard::into_guard(guard))
+    }
+
+    /// Stop tracking mutable access and return the underlying guard, which
+    /// runs its closure regardless of whether the value was modified.
+    pub fn into_guard(guard: Self) -> ScopeGuard<T, F, S> {
+        // Cannot move out of `Drop`-implementing types,
+        // so take the guard out and forget the wrapper.
+        let guard = ManuallyDrop::new(guard);
+        unsafe { ptr::read(&guard.guard) }
+    }
+}
+
+impl<T, F, S> Deref for DirtyGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T, F, S> DerefMut for DirtyGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        self.dirty = true;
+        &mut self.guard
+    }
+}
+
+impl<T, F, S> Drop for DirtyGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn drop(&mut self) {
+        if !self.dirty {
+            ScopeGuard::disarm(&mut self.guard);
+        }
+    }
+}
+
+impl<T, F, S> fmt::Debug for DirtyGuard<T, F, S>
+where
+    T: fmt::Debug,
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(DirtyGuard))
+            .field("value", &**self)
+            .field("dirty", &self.dirty)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_dirty_guard() {
+        let runs = Cell::new(0);
+        let mut g = DirtyGuard::new(1, |_| runs.set(runs.get() + 1));
+        assert!(!DirtyGuard::is_dirty(&g));
+        *g += 1;
+        assert!(DirtyGuard::is_dirty(&g));
+        DirtyGuard::mark_clean(&mut g);
+        drop(g);
+        assert_eq!(runs.get(), 0);
+
+        let g = DirtyGuard::new(1, |_| runs.set(runs.get() + 1));
+        drop(DirtyGuard::into_guard(g));
+        assert_eq!(runs.get(), 1);
+    }
+}
@@ -0,0 +1,496 @@
+//! Guards for futures.
+//!
+//! The `TryFuture` combinators and the `Sink` wrapper require crate feature
+//! `futures`.
+
+#[cfg(feature = "futures")]
+use futures_core::future::TryFuture;
+#[cfg(feature = "futures")]
+use futures_sink::Sink;
+#[cfg(feature = "futures")]
+use std::fmt;
+use std::future::Future;
+#[cfg(feature = "futures")]
+use std::marker::PhantomData;
+use std::mem::ManuallyDrop;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use guard;
+
+/// Wrap `future` so that `cleanup` runs when it finishes by any means.
+///
+/// `cleanup` runs once, either when the future completes (before its output
+/// is returned), or when the wrapper is dropped before completion, for
+/// example when the future is cancelled. In the latter case the inner future
+/// is dropped first. This gives try/finally semantics across await points.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::Cell;
+/// # use std::future::Future;
+/// # use std::pin::Pin;
+/// # use std::task::{Context, Poll};
+/// # struct Pending;
+/// # impl Future for Pending {
+/// #     type Output = ();
+/// #     fn poll(self: Pin<&mut Self>, _: &mut Context) -> Poll<()> { Poll::Pending }
+/// # }
+/// # fn pending() -> Pending { Pending }
+///
+/// fn main() {
+///     let cleaned = Cell::new(false);
+///     let fut = scopeguard::future::guarded_future(pending(), || cleaned.set(true));
+///     // cancelled without ever completing
+///     drop(fut);
+///     assert!(cleaned.get());
+/// }
+/// ```
+pub fn guarded_future<Fut, F>(future: Fut, cleanup: F) -> Guarded<Fut, F>
+where
+    Fut: Future,
+    F: FnOnce(),
+{
+    Guarded {
+        future: ManuallyDrop::new(future),
+        cleanup: Some(cleanup),
+    }
+}
+
+/// Future for the [`guarded_future`](fn.guarded_future.html) function.
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Guarded<Fut, F>
+where
+    F: FnOnce(),
+{
+    future: ManuallyDrop<Fut>,
+    cleanup: Option<F>,
+}
+
+impl<Fut, F> Future for Guarded<Fut, F>
+where
+    Fut: Future,
+    F: FnOnce(),
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `future` is structurally pinned, `cleanup` is not.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut *this.future) };
+        let output = future.poll(cx);
+        if output.is_ready() {
+            if let Some(cleanup) = this.cleanup.take() {
+                cleanup();
+            }
+        }
+        output
+    }
+}
+
+impl<Fut, F> Drop for Guarded<Fut, F>
+where
+    F: FnOnce(),
+{
+    fn drop(&mut self) {
+        // Run the cleanup even if dropping the future panics.
+        let _cleanup = guard(self.cleanup.take(), |cleanup| {
+            if let Some(cleanup) = cleanup {
+                cleanup();
+            }
+        });
+        // Dropping in place is allowed for pinned data.
+        unsafe { ManuallyDrop::drop(&mut self.future) }
+    }
+}
+
+/// Extension methods for `TryFuture`.
+///
+/// Requires crate feature `futures`.
+#[cfg(feature = "futures")]
+pub trait TryFutureGuardExt: TryFuture + Sized {
+    /// Run `f` with the error if the future resolves to `Err`.
+    ///
+    /// The closure is not called if the future resolves to `Ok`, or if it is
+    /// dropped before completion. The error is passed through unchanged.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::future::TryFutureGuardExt;
+    /// use std::future::{ready, Future};
+    /// # use std::pin::Pin;
+    /// # use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+    /// # fn block_on<F: Future + Unpin>(mut f: F) -> F::Output {
+    /// #     const VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    /// #     unsafe fn clone(_: *const ()) -> RawWaker {
+    /// #         RawWaker::new(std::ptr::null(), &VTABLE)
+    /// #     }
+    /// #     unsafe fn noop(_: *const ()) {}
+    /// #     let waker = unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) };
+    /// #     match Pin::new(&mut f).poll(&mut Context::from_waker(&waker)) {
+    /// #         Poll::Ready(x) => x,
+    /// #         Poll::Pending => unreachable!(),
+    /// #     }
+    /// # }
+    ///
+    /// fn main() {
+    ///     let mut rolled_back = false;
+    ///     let fut = ready(Err::<(), _>("failed")).on_err(|_| rolled_back = true);
+    ///     assert_eq!(block_on(fut), Err("failed"));
+    ///     assert!(rolled_back);
+    /// }
+    /// ```
+    fn on_err<F>(self, f: F) -> OnErr<Self, F>
+    where
+        F: FnOnce(&Self::Error),
+    {
+        OnErr {
+            future: self,
+            f: Some(f),
+        }
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<Fut: TryFuture> TryFutureGuardExt for Fut {}
+
+/// Future for the [`on_err`](trait.TryFutureGuardExt.html#method.on_err)
+/// method.
+///
+/// Requires crate feature `futures`.
+#[cfg(feature = "futures")]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct OnErr<Fut, F> {
+    future: Fut,
+    f: Option<F>,
+}
+
+#[cfg(feature = "futures")]
+impl<Fut, F> Future for OnErr<Fut, F>
+where
+    Fut: TryFuture,
+    F: FnOnce(&Fut::Error),
+{
+    type Output = Result<Fut::Ok, Fut::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // `future` is structurally pinned, `f` is not.
+        let this = unsafe { self.get_unchecked_mut() };
+        let future = unsafe { Pin::new_unchecked(&mut this.future) };
+        match future.try_poll(cx) {
+            Poll::Ready(Err(e)) => {
+                if let Some(f) = this.f.take() {
+                    f(&e);
+                }
+                Poll::Ready(Err(e))
+            }
+            poll => poll,
+        }
+    }
+}
+
+/// Wrap `sink` so that it is closed when the wrapper is dropped without
+/// having been closed.
+///
+/// A destructor can't wait for the sink to flush its buffered items, so on
+/// drop the sink is moved into a [`Close`](struct.Close.html) future, which
+/// is passed to `on_drop`. The closure can spawn it on an executor or block
+/// on it. Once the sink has been closed through the wrapper, `on_drop` is not
+/// called.
+///
+/// Requires crate feature `futures`.
+///
+/// ```
+/// # extern crate futures_sink;
+/// extern crate scopeguard;
+///
+/// use scopeguard::future::close_on_drop;
+/// # use std::pin::Pin;
+/// # use std::task::{Context, Poll};
+/// # struct Channel;
+/// # impl futures_sink::Sink<u8> for Channel {
+/// #     type Error = ();
+/// #     fn poll_ready(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), ()>> { Poll::Ready(Ok(())) }
+/// #     fn start_send(self: Pin<&mut Self>, _: u8) -> Result<(), ()> { Ok(()) }
+/// #     fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), ()>> { Poll::Ready(Ok(())) }
+/// #     fn poll_close(self: Pin<&mut Self>, _: &mut Context) -> Poll<Result<(), ()>> { Poll::Ready(Ok(())) }
+/// # }
+/// # fn spawn<F>(_: F) {}
+///
+/// fn main() {
+///     let channel = close_on_drop(Channel, |close| spawn(close));
+///     // return early without closing the channel
+///     drop(channel);
+/// }
+/// ```
+#[cfg(feature = "futures")]
+pub fn close_on_drop<Si, Item, F>(sink: Si, on_drop: F) -> CloseOnDrop<Si, Item, F>
+where
+    Si: Sink<Item> + Unpin,
+    F: FnOnce(Close<Si, Item>),
+{
+    CloseOnDrop {
+        sink: Some(sink),
+        on_drop: Some(on_drop),
+        closed: false,
+        item: PhantomData,
+    }
+}
+
+/// `Sink` wrapper for [`close_on_drop`](fn.close_on_drop.html).
+///
+/// Requires crate feature `futures`.
+#[cfg(feature = "futures")]
+pub struct CloseOnDrop<Si, Item, F>
+where
+    Si: Sink<Item> + Unpin,
+    F: FnOnce(Close<Si, Item>),
+{
+    sink: Option<Si>,
+    on_drop: Option<F>,
+    closed: bool,
+    item: PhantomData<fn(Item)>,
+}
+
+#[cfg(feature = "futures")]
+impl<Si, Item, F> CloseOnDrop<Si, Item, F>
+where
+    Si: Sink<Item> + Unpin,
+    F: FnOnce(Close<Si, Item>),
+{
+    /// Return a reference to the sink.
+    pub fn get_ref(&self) -> &Si {
+        self.sink.as_ref().unwrap()
+    }
+
+    /// Return a mutable reference to the sink.
+    pub fn get_mut(&mut self) -> &mut Si {
+        self.sink.as_mut().unwrap()
+    }
+
+    /// Return the sink without closing it.
+    pub fn into_inner(mut self) -> Si {
+        self.sink.take().unwrap()
+    }
+}
+
+// The closure is never pinned.
+#[cfg(feature = "futures")]
+impl<Si, Item, F> Unpin for CloseOnDrop<Si, Item, F>
+where
+    Si: Sink<Item> + Unpin,
+    F: FnOnce(Close<Si, Item>),
+{
+}
+
+#[cfg(feature = "futures")]
+impl<Si, Item, F> Sink<Item> for CloseOnDrop<Si, Item, F>
+where
+    Si: Sink<Item> + Unpin,
+    F: FnOnce(Close<Si, Item>),
+{
+    type Error = Si::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        Pin::new(self.get_mut().get_mut()).poll_ready(cx)
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Item) -> Result<(), Si::Error> {
+        Pin::new(self.get_mut().get_mut()).start_send(item)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        Pin::new(self.get_mut().get_mut()).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Si::Error>> {
+        let this = self.get_mut();
+        let poll = Pin::new(this.get_mut()).poll_close(cx);
+        if poll.is_ready() {
+            this.closed = true;
+        }
+        poll
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<Si, Item, F> Drop for CloseOnDrop<Si, Item, F>
+where
+    Si: Sink<Item> + Unpin,
+    F: FnOnce(Close<Si, Item>),
+{
+    fn drop(&mut self) {
+        if let (Some(sink), Some(on_drop)) = (self.sink.take(), self.on_drop.take()) {
+            if !self.closed {
+                on_drop(Close {
+                    sink,
+                    item: PhantomData,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(feature = "futures")]
+impl<Si, Item, F> fmt::Debug for CloseOnDrop<Si, Item, F>
+where
+    Si: Sink<Item> + Unpin + fmt::Debug,
+    F: FnOnce(Close<Si, Item>),
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct(stringify!(CloseOnDrop))
+            .field("sink", &self.sink)
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+/// Future that closes a sink dropped by
+/// [`CloseOnDrop`](struct.CloseOnDrop.html).
+///
+/// Requires crate feature `futures`.
+#[cfg(feature = "futures")]
+#[derive(Debug)]
+#[must_use = "futures do nothing unless you `.await` or poll them"]
+pub struct Close<Si, Item> {
+    sink: Si,
+    item: PhantomData<fn(Item)>,
+}
+
+#[cfg(feature = "futures")]
+impl<Si, Item> Future for Close<Si, Item>
+where
+    Si: Sink<Item> + Unpin,
+{
+    type Output = Result<(), Si::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.get_mut().sink).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::ptr;
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    struct Ready<T>(Option<T>);
+
+    impl<T: Unpin> Future for Ready<T> {
+        type Output = T;
+
+        fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<T> {
+            Poll::Ready(self.0.take().unwrap())
+        }
+    }
+
+    fn ready<T>(value: T) -> Ready<T> {
+        Ready(Some(value))
+    }
+
+    fn noop_waker() -> Waker {
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        unsafe fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(ptr::null(), &VTABLE)
+        }
+        unsafe fn noop(_: *const ()) {}
+        unsafe { Waker::from_raw(RawWaker::new(ptr::null(), &VTABLE)) }
+    }
+
+    fn poll_once<Fut: Future + Unpin>(mut fut: Fut) -> Poll<Fut::Output> {
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        Pin::new(&mut fut).poll(&mut cx)
+    }
+
+    #[test]
+    fn test_guarded_future_runs_once() {
+        let cleanups = Cell::new(0);
+        let mut fut = guarded_future(ready(5), || cleanups.set(cleanups.get() + 1));
+        assert_eq!(poll_once(&mut fut), Poll::Ready(5));
+        assert_eq!(cleanups.get(), 1);
+        drop(fut);
+        assert_eq!(cleanups.get(), 1);
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn test_on_err() {
+        let errors = Cell::new(0);
+        let ok = ready(Ok::<_, ()>(1)).on_err(|_| errors.set(errors.get() + 1));
+        assert_eq!(poll_once(ok), Poll::Ready(Ok(1)));
+        assert_eq!(errors.get(), 0);
+        let err = ready(Err::<(), _>(2)).on_err(|e| errors.set(errors.get() + e));
+        assert_eq!(poll_once(err), Poll::Ready(Err(2)));
+        assert_eq!(errors.get(), 2);
+    }
+
+    #[cfg(feature = "futures")]
+    struct Buffered {
+        buffered: usize,
+        sent: std::rc::Rc<Cell<usize>>,
+    }
+
+    #[cfg(feature = "futures")]
+    impl Sink<u8> for Buffered {
+        type Error = ();
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, _: u8) -> Result<(), ()> {
+            self.get_mut().buffered += 1;
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            let this = self.get_mut();
+            this.sent.set(this.sent.get() + this.buffered);
+            this.buffered = 0;
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), ()>> {
+            self.poll_flush(cx)
+        }
+    }
+
+    #[cfg(feature = "futures")]
+    #[test]
+    fn test_close_on_drop() {
+        let sent = std::rc::Rc::new(Cell::new(0));
+        let closes = Cell::new(0);
+        let sink = Buffered {
+            buffered: 0,
+            sent: sent.clone(),
+        };
+        let mut sink = close_on_drop(sink, |close| {
+            closes.set(closes.get() + 1);
+            assert_eq!(poll_once(close), Poll::Ready(Ok(())));
+        });
+        Pin::new(&mut sink).start_send(1).unwrap();
+        Pin::new(&mut sink).start_send(2).unwrap();
+        drop(sink);
+        assert_eq!((sent.get(), closes.get()), (2, 1));
+
+        let mut sink = close_on_drop(
+            Buffered {
+                buffered: 0,
+                sent: sent.clone(),
+            },
+            |_| closes.set(closes.get() + 1),
+        );
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut sink).poll_close(&mut cx).is_ready());
+        drop(sink);
+        assert_eq!(closes.get(), 1);
+    }
+}
@@ -1,4 +1,5 @@
 #![cfg_attr(not(any(test, feature = "use_std")), no_std)]
+#![cfg_attr(feature = "allocator_api", feature(allocator_api))]
 #![doc(html_root_url = "https://docs.rs/scopeguard/1/")]
 
 //! A scope guard will run a given closure when it goes out of scope,
@@ -106,7 +107,7 @@
 //! ```
 //! extern crate scopeguard;
 //!
-//! use std::mem::ManuallyDrop;
+//! use std::mem::{self, ManuallyDrop};
 //! use std::ptr;
 //!
 //! // This function, just for this example, takes the first element
@@ -177,31 +178,185 @@
 //! # Crate Features
 //!
 //! - `use_std`
-//!   + Enabled by default. Enables the `OnUnwind` and `OnSuccess` strategies.
+//!   + Enabled by default. Lets the `OnUnwind` and `OnSuccess` strategies
+//!     detect unwinding; without it, they rely on
+//!     [`set_panicking_hook`](fn.set_panicking_hook.html) or
+//!     [`panic_count`](panic_count/index.html).
 //!   + Disable to use `no_std`.
+//! - `alloc`
+//!   + Enabled by `use_std`. Enables the boxed guard types, such as
+//!     [`BoxedGuard`](type.BoxedGuard.html), and the
+//!     [`shared`](shared/index.html) and [`teardown`](teardown/index.html)
+//!     modules in `no_std` builds.
+//! - `allocator`
+//!   + Enables the [`allocator`](allocator/index.html) module, with a global
+//!     allocator and guards that observe allocations. Implies `use_std`.
+//! - `allocator_api`
+//!   + Requires a nightly compiler. Makes the boxed guard types generic over
+//!     the allocator of the box, with constructors such as
+//!     [`guard_boxed_in`](fn.guard_boxed_in.html). Implies `alloc`.
+//! - `abort_warning`
+//!   + In debug builds of programs built with `panic = "abort"`, warns once on
+//!     stderr when an [`OnUnwind`](struct.OnUnwind.html) guard, which can never
+//!     run there, is dropped. Implies `use_std`.
+//! - `debug_info`
+//!   + Stores the name and caller location set through
+//!     [`builder`](fn.builder.html) in the guard, and shows them in its `Debug`
+//!     output.
+//! - `derive`
+//!   + Enables the [`Finalize`](derive.Finalize.html) derive macro, which
+//!     generates a `Drop` implementation from per-field cleanup annotations.
+//!     Also enables the [`deferred`](attr.deferred.html) attribute, which runs
+//!     an expression at every exit of a function.
+//! - `serde`
+//!   + Implements `Serialize` for `ScopeGuard`, serializing the protected
+//!     value.
+//! - `log`
+//!   + Enables the `log` error policy of [`try_defer!`](macro.try_defer.html),
+//!     which logs errors of deferred cleanups with the `log` crate. Also logs
+//!     the cleanups that [`guard_non_reentrant`](fn.guard_non_reentrant.html)
+//!     skips.
+//! - `metrics`
+//!   + Enables [`guard_timed_histogram`](fn.guard_timed_histogram.html), which
+//!     records how long a cleanup takes in a histogram of the `metrics` crate.
+//! - `futures`
+//!   + Enables the `TryFuture` combinators and the `Sink` wrapper in the
+//!     [`future`](future/index.html) module.
+//! - `tokio`
+//!   + Enables the [`task_local`](task_local/index.html) module, with guards
+//!     for Tokio task-local values, and the [`spawn`](spawn/index.html) module
+//!     with Tokio as executor.
+//! - `async-std`, `smol`
+//!   + Enable the [`spawn`](spawn/index.html) module with the respective
+//!     executor, for guards whose cleanup is an asynchronous task.
+//! - `wasm`
+//!   + Enables the [`spawn`](spawn/index.html) module on `wasm32` targets, for
+//!     guards whose cleanup is scheduled on the browser's microtask queue.
+//! - `unix`
+//!   + Enables the [`unix`](unix/index.html) module on Unix targets, with
+//!     guards for processes and system resources. Implies `use_std`.
 //!
 //! # Rust Version
 //!
-//! This version of the crate requires Rust 1.20 or later.
+//! This version of the crate requires Rust 1.61 or later.
 //!
 //! The scopeguard 1.x release series will use a carefully considered version
 //! upgrade policy, where in a later 1.x version, we will raise the minimum
 //! required Rust version.
 
+#[cfg(feature = "alloc")]
+extern crate alloc;
+#[cfg(feature = "async-std")]
+extern crate async_std;
 #[cfg(not(any(test, feature = "use_std")))]
 extern crate core as std;
+#[cfg(feature = "futures")]
+extern crate futures_core;
+#[cfg(feature = "futures")]
+extern crate futures_sink;
+#[cfg(all(unix, feature = "unix"))]
+extern crate libc;
+#[cfg(feature = "log")]
+extern crate log;
+#[cfg(feature = "metrics")]
+extern crate metrics;
+#[cfg(feature = "derive")]
+extern crate scopeguard_derive;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_test;
+#[cfg(feature = "smol")]
+extern crate smol;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+extern crate wasm_bindgen_futures;
 
+use std::any::type_name;
+use std::cmp;
 use std::fmt;
-use std::marker::PhantomData;
+use std::hash::{Hash, Hasher};
 use std::mem::ManuallyDrop;
 use std::ops::{Deref, DerefMut};
+#[cfg(feature = "debug_info")]
+use std::panic::Location;
+use std::pin::Pin;
 use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+
+#[cfg(feature = "allocator_api")]
+use alloc::alloc::{Allocator, Global};
+#[cfg(feature = "alloc")]
+use alloc::boxed::Box;
+
+#[cfg(feature = "derive")]
+pub use scopeguard_derive::deferred;
+#[cfg(feature = "derive")]
+pub use scopeguard_derive::Finalize;
+
+#[cfg(feature = "allocator")]
+pub mod allocator;
+pub mod dirty;
+pub mod future;
+#[cfg(target_has_atomic = "ptr")]
+pub mod panic_count;
+pub mod pool;
+#[cfg(feature = "alloc")]
+pub mod shared;
+#[cfg(any(
+    feature = "tokio",
+    feature = "async-std",
+    feature = "smol",
+    all(feature = "wasm", target_arch = "wasm32")
+))]
+pub mod spawn;
+#[cfg(feature = "tokio")]
+pub mod task_local;
+#[cfg(feature = "alloc")]
+pub mod teardown;
+#[cfg(feature = "use_std")]
+pub mod testing;
+#[cfg(all(unix, feature = "unix"))]
+pub mod unix;
+#[cfg(feature = "use_std")]
+pub mod work;
 
 /// Controls in which cases the associated code should be run
+///
+/// The guard stores its strategy, so a strategy can hold state that decides
+/// whether the closure runs. The strategies of this crate are zero-sized.
+///
+/// The guard's auto traits, like `Send` and `Sync`, depend on the strategy
+/// like they do on the value and the closure. The strategies of this crate
+/// implement all of them.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::{ScopeGuard, Strategy};
+///
+/// // Run only if the guard is dropped after `deadline`.
+/// struct After {
+///     deadline: u64,
+///     now: fn() -> u64,
+/// }
+///
+/// impl Strategy for After {
+///     fn should_run(&self) -> bool {
+///         (self.now)() >= self.deadline
+///     }
+/// }
+///
+/// fn main() {
+///     let strategy = After { deadline: 10, now: || 5 };
+///     let _guard = ScopeGuard::new((), |()| panic!("too early"), strategy);
+/// }
+/// ```
 pub trait Strategy {
     /// Return `true` if the guard’s associated code should run
     /// (in the context where this method is called).
-    fn should_run() -> bool;
+    fn should_run(&self) -> bool;
 }
 
 /// Always run on scope exit.
@@ -209,387 +364,4715 @@ pub trait Strategy {
 /// “Always” run: on regular exit from a scope or on unwinding from a panic.
 /// Can not run on abort, process exit, and other catastrophic events where
 /// destructors don’t run.
-#[derive(Debug)]
-pub enum Always {}
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Always;
+
+/// Never run on scope exit.
+///
+/// Use it to disable guards in generic code or in some build configurations,
+/// for example `type CleanupStrategy = Never;` in a fast path. The value is
+/// still dropped.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Never;
 
 /// Run on scope exit through unwinding.
 ///
-/// Requires crate feature `use_std`.
-#[cfg(feature = "use_std")]
-#[derive(Debug)]
-pub enum OnUnwind {}
+/// Such a guard never runs in a program built with `panic = "abort"`.
+/// With crate feature `abort_warning`, debug builds of such a program print a
+/// warning (once) to stderr when an armed `OnUnwind` or
+/// [`OnNewUnwind`](struct.OnNewUnwind.html) guard is dropped.
+///
+/// Without crate feature `use_std`, unwinding is detected through the hook
+/// installed with [`set_panicking_hook`](fn.set_panicking_hook.html) or the
+/// [`panic_count`](panic_count/index.html) module.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OnUnwind;
 
 /// Run on regular scope exit, when not unwinding.
 ///
-/// Requires crate feature `use_std`.
-#[cfg(feature = "use_std")]
-#[derive(Debug)]
-pub enum OnSuccess {}
+/// Without crate feature `use_std`, unwinding is detected through the hook
+/// installed with [`set_panicking_hook`](fn.set_panicking_hook.html) or the
+/// [`panic_count`](panic_count/index.html) module.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct OnSuccess;
 
 impl Strategy for Always {
     #[inline(always)]
-    fn should_run() -> bool {
+    fn should_run(&self) -> bool {
         true
     }
 }
 
-#[cfg(feature = "use_std")]
+impl Strategy for Never {
+    #[inline(always)]
+    fn should_run(&self) -> bool {
+        false
+    }
+}
+
 impl Strategy for OnUnwind {
     #[inline]
-    fn should_run() -> bool {
-        std::thread::panicking()
+    fn should_run(&self) -> bool {
+        #[cfg(all(feature = "abort_warning", debug_assertions, panic = "abort"))]
+        warn_unwind_guard_under_abort();
+        #[cfg(feature = "use_std")]
+        {
+            if let Some(run) = thread_override(&ON_UNWIND_OVERRIDE) {
+                return run;
+            }
+        }
+        panicking()
     }
 }
 
-#[cfg(feature = "use_std")]
 impl Strategy for OnSuccess {
     #[inline]
-    fn should_run() -> bool {
-        !std::thread::panicking()
+    fn should_run(&self) -> bool {
+        #[cfg(feature = "use_std")]
+        {
+            if let Some(run) = thread_override(&ON_SUCCESS_OVERRIDE) {
+                return run;
+            }
+        }
+        !panicking()
     }
 }
 
-/// Macro to create a `ScopeGuard` (always run).
-///
-/// The macro takes statements, which are the body of a closure
-/// that will run when the scope is exited.
-#[macro_export]
-macro_rules! defer {
-    ($($t:tt)*) => {
-        let _guard = $crate::guard((), |()| { $($t)* });
-    };
-}
-
-/// Macro to create a `ScopeGuard` (run on successful scope exit).
-///
-/// The macro takes statements, which are the body of a closure
-/// that will run when the scope is exited.
-///
-/// Requires crate feature `use_std`.
-#[cfg(feature = "use_std")]
-#[macro_export]
-macro_rules! defer_on_success {
-    ($($t:tt)*) => {
-        let _guard = $crate::guard_on_success((), |()| { $($t)* });
-    };
-}
-
-/// Macro to create a `ScopeGuard` (run on unwinding from panic).
+/// Run on scope exit through unwinding, if the panic began after the guard
+/// was created.
 ///
-/// The macro takes statements, which are the body of a closure
-/// that will run when the scope is exited.
+/// [`OnUnwind`](struct.OnUnwind.html) also runs guards that were created while
+/// the thread was already unwinding, for example in a `Drop` implementation
+/// that runs during unwinding, even if their own scope exits normally. A guard
+/// with this strategy records whether the thread is unwinding when the
+/// strategy is created, and does not run if it was.
 ///
-/// Requires crate feature `use_std`.
-#[cfg(feature = "use_std")]
-#[macro_export]
-macro_rules! defer_on_unwind {
-    ($($t:tt)*) => {
-        let _guard = $crate::guard_on_unwind((), |()| { $($t)* });
-    };
-}
-
-/// `ScopeGuard` is a scope guard that may own a protected value.
+/// A panic that starts while the thread is unwinding must be caught before it
+/// leaves a destructor, so when such a guard is dropped, the thread can only be
+/// unwinding from a panic that began after the guard was created.
 ///
-/// If you place a guard in a local variable, the closure can
-/// run regardless how you leave the scope — through regular return or panic
-/// (except if panic or other code aborts; so as long as destructors run).
-/// It is run only once.
+/// ```
+/// extern crate scopeguard;
 ///
-/// The `S` parameter for [`Strategy`](trait.Strategy.html) determines if
-/// the closure actually runs.
+/// use scopeguard::{guard_with, OnNewUnwind};
 ///
-/// The guard's closure will be called with the held value in the destructor.
+/// struct Connection;
 ///
-/// The `ScopeGuard` implements `Deref` so that you can access the inner value.
-pub struct ScopeGuard<T, F, S = Always>
-where
-    F: FnOnce(T),
-    S: Strategy,
-{
-    value: ManuallyDrop<T>,
-    dropfn: ManuallyDrop<F>,
-    // fn(S) -> S is used, so that the S is not taken into account for auto traits.
-    strategy: PhantomData<fn(S) -> S>,
+/// impl Drop for Connection {
+///     fn drop(&mut self) {
+///         // Only log panics of the cleanup itself, not the panic that is
+///         // dropping the connection.
+///         let _log = guard_with::<OnNewUnwind, _, _>((), |()| {
+///             eprintln!("closing the connection panicked");
+///         });
+///         // close the connection
+///     }
+/// }
+/// # fn main() {
+/// #     drop(Connection);
+/// # }
+/// ```
+#[derive(Copy, Clone, Debug)]
+pub struct OnNewUnwind {
+    unwinding_at_creation: bool,
 }
 
-impl<T, F, S> ScopeGuard<T, F, S>
-where
-    F: FnOnce(T),
-    S: Strategy,
-{
-    /// Create a `ScopeGuard` that owns `v` (accessible through deref) and calls
-    /// `dropfn` when its destructor runs.
-    ///
-    /// The `Strategy` decides whether the scope guard's closure should run.
+impl OnNewUnwind {
+    /// Create the strategy, recording whether the current thread is unwinding.
     #[inline]
-    #[must_use]
-    pub fn with_strategy(v: T, dropfn: F) -> ScopeGuard<T, F, S> {
-        ScopeGuard {
-            value: ManuallyDrop::new(v),
-            dropfn: ManuallyDrop::new(dropfn),
-            strategy: PhantomData,
+    pub fn new() -> Self {
+        OnNewUnwind {
+            unwinding_at_creation: panicking(),
         }
     }
+}
 
-    /// “Defuse” the guard and extract the value without calling the closure.
-    ///
-    /// ```
-    /// extern crate scopeguard;
-    ///
-    /// use scopeguard::{guard, ScopeGuard};
-    ///
-    /// fn conditional() -> bool { true }
-    ///
-    /// fn main() {
-    ///     let mut guard = guard(Vec::new(), |mut v| v.clear());
-    ///     guard.push(1);
-    ///
-    ///     if conditional() {
-    ///         // a condition maybe makes us decide to
-    ///         // “defuse” the guard and get back its inner parts
-    ///         let value = ScopeGuard::into_inner(guard);
-    ///     } else {
-    ///         // guard still exists in this branch
-    ///     }
-    /// }
-    /// ```
+impl Default for OnNewUnwind {
     #[inline]
-    pub fn into_inner(guard: Self) -> T {
-        // Cannot move out of `Drop`-implementing types,
-        // so `ptr::read` the value and forget the guard.
-        let mut guard = ManuallyDrop::new(guard);
-        unsafe {
-            let value = ptr::read(&*guard.value);
-            // Drop the closure after `value` has been read, so that if the
-            // closure's `drop` function panics, unwinding still tries to drop
-            // `value`.
-            ManuallyDrop::drop(&mut guard.dropfn);
-            value
-        }
+    fn default() -> Self {
+        OnNewUnwind::new()
     }
 }
 
-/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`.
-#[inline]
-#[must_use]
-pub fn guard<T, F>(v: T, dropfn: F) -> ScopeGuard<T, F, Always>
-where
-    F: FnOnce(T),
-{
-    ScopeGuard::with_strategy(v, dropfn)
+impl Strategy for OnNewUnwind {
+    #[inline]
+    fn should_run(&self) -> bool {
+        #[cfg(all(feature = "abort_warning", debug_assertions, panic = "abort"))]
+        warn_unwind_guard_under_abort();
+        !self.unwinding_at_creation && panicking()
+    }
 }
 
-/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`.
-///
-/// Requires crate feature `use_std`.
-#[cfg(feature = "use_std")]
-#[inline]
-#[must_use]
-pub fn guard_on_success<T, F>(v: T, dropfn: F) -> ScopeGuard<T, F, OnSuccess>
-where
-    F: FnOnce(T),
-{
-    ScopeGuard::with_strategy(v, dropfn)
+// Checked when the guard is dropped, since `ScopeGuard::new` is a `const fn`
+// and every guard, however it was created, is dropped through the same path.
+#[cfg(all(feature = "abort_warning", debug_assertions, panic = "abort"))]
+#[cold]
+fn warn_unwind_guard_under_abort() {
+    static WARN: std::sync::Once = std::sync::Once::new();
+    WARN.call_once(|| {
+        eprintln!(
+            "scopeguard: an unwinding guard was dropped in a program built with \
+             `panic = \"abort\"`; it never runs"
+        );
+    });
 }
 
-/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`.
-///
-/// Requires crate feature `use_std`.
-///
-/// ## Examples
+// A `fn() -> bool`, or null if no hook is installed.
+static PANICKING_HOOK: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Install `hook` to tell the [`OnUnwind`](struct.OnUnwind.html) and
+/// [`OnSuccess`](struct.OnSuccess.html) strategies whether the current thread
+/// is unwinding from a panic.
 ///
-/// For performance reasons, or to emulate “only run guard on unwind” in
-/// no-std environments, we can also use the default guard and simply manually
-/// defuse it at the end of scope like the following example. (The performance
-/// reason would be if the [`OnUnwind`]'s call to [std::thread::panicking()] is
-/// an issue.)
+/// This makes these strategies usable in `no_std` programs that unwind and
+/// track the panic state themselves. With crate feature `use_std`, the hook
+/// replaces `std::thread::panicking`. Without the feature and without a hook,
+/// the strategies use the [`panic_count`](panic_count/index.html) module in
+/// programs built with `panic = "unwind"`, and otherwise assume that the thread
+/// is never unwinding.
 ///
 /// ```
 /// extern crate scopeguard;
 ///
-/// use scopeguard::ScopeGuard;
-/// # fn main() {
-/// {
-///     let guard = scopeguard::guard((), |_| {});
+/// use std::sync::atomic::{AtomicBool, Ordering};
 ///
-///     // rest of the code here
+/// // Set by the program's panic handler, cleared where panics are caught.
+/// static PANICKING: AtomicBool = AtomicBool::new(false);
 ///
-///     // we reached the end of scope without unwinding - defuse it
-///     ScopeGuard::into_inner(guard);
+/// fn main() {
+///     scopeguard::set_panicking_hook(|| PANICKING.load(Ordering::SeqCst));
+///     let _g = scopeguard::guard_on_unwind((), |()| panic!("not unwinding"));
 /// }
-/// # }
 /// ```
-#[cfg(feature = "use_std")]
-#[inline]
-#[must_use]
-pub fn guard_on_unwind<T, F>(v: T, dropfn: F) -> ScopeGuard<T, F, OnUnwind>
-where
-    F: FnOnce(T),
-{
-    ScopeGuard::with_strategy(v, dropfn)
+pub fn set_panicking_hook(hook: fn() -> bool) {
+    PANICKING_HOOK.store(hook as *mut (), Ordering::Release);
 }
 
-// ScopeGuard can be Sync even if F isn't because the closure is
-// not accessible from references.
-// The guard does not store any instance of S, so it is also irrelevant.
-unsafe impl<T, F, S> Sync for ScopeGuard<T, F, S>
-where
-    T: Sync,
-    F: FnOnce(T),
-    S: Strategy,
-{
+/// Remove the hook installed with
+/// [`set_panicking_hook`](fn.set_panicking_hook.html), restoring the default
+/// detection of unwinding.
+pub fn reset_panicking_hook() {
+    PANICKING_HOOK.store(ptr::null_mut(), Ordering::Release);
 }
 
-impl<T, F, S> Deref for ScopeGuard<T, F, S>
-where
-    F: FnOnce(T),
-    S: Strategy,
-{
-    type Target = T;
+/// Return `true` if the current thread is unwinding from a panic, as far as
+/// the unwind-aware strategies can tell.
+#[inline]
+fn panicking() -> bool {
+    let hook = PANICKING_HOOK.load(Ordering::Acquire);
+    if !hook.is_null() {
+        // Only `set_panicking_hook` stores into the hook, and it stores a
+        // `fn() -> bool`.
+        let hook = unsafe { std::mem::transmute::<*mut (), fn() -> bool>(hook) };
+        return hook();
+    }
+    #[cfg(feature = "use_std")]
+    {
+        std::thread::panicking()
+    }
+    #[cfg(all(not(feature = "use_std"), panic = "unwind", target_has_atomic = "ptr"))]
+    {
+        panic_count::get() != 0
+    }
+    #[cfg(all(
+        not(feature = "use_std"),
+        not(all(panic = "unwind", target_has_atomic = "ptr"))
+    ))]
+    {
+        false
+    }
+}
 
-    fn deref(&self) -> &T {
-        &*self.value
+/// Run on scope exit if the flag is `true`.
+///
+/// The flag is decided at runtime, usually when the guard is created with
+/// [`guard_if`](fn.guard_if.html), and can be changed later through
+/// [`ScopeGuard::strategy_mut`](struct.ScopeGuard.html#method.strategy_mut).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct WhenFlag(pub bool);
+
+impl Strategy for WhenFlag {
+    #[inline]
+    fn should_run(&self) -> bool {
+        self.0
     }
 }
 
-impl<T, F, S> DerefMut for ScopeGuard<T, F, S>
+/// Run on scope exit if the shared flag is set.
+///
+/// The flag is read when the guard is dropped, so another thread can decide
+/// whether the closure runs. `A` is any reference to an `AtomicBool`, such as
+/// `&'static AtomicBool` or `Arc<AtomicBool>`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use std::sync::Arc;
+/// use std::thread;
+/// use scopeguard::{ScopeGuard, WhenAtomicFlag};
+///
+/// fn main() {
+///     let failed = Arc::new(AtomicBool::new(false));
+///     let worker = {
+///         let failed = failed.clone();
+///         thread::spawn(move || {
+///             let _undo = ScopeGuard::new((), |()| println!("undo"), WhenAtomicFlag(failed));
+///             // do the work
+///         })
+///     };
+///     // The coordinator detects a failure elsewhere.
+///     failed.store(true, Ordering::SeqCst);
+///     worker.join().unwrap();
+/// }
+/// ```
+#[derive(Copy, Clone, Debug, Default)]
+pub struct WhenAtomicFlag<A>(pub A);
+
+impl<A> Strategy for WhenAtomicFlag<A>
 where
-    F: FnOnce(T),
-    S: Strategy,
+    A: Deref<Target = AtomicBool>,
 {
-    fn deref_mut(&mut self) -> &mut T {
-        &mut *self.value
+    #[inline]
+    fn should_run(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
     }
 }
 
-impl<T, F, S> Drop for ScopeGuard<T, F, S>
-where
-    F: FnOnce(T),
-    S: Strategy,
-{
-    fn drop(&mut self) {
-        // This is OK because the fields are `ManuallyDrop`s
-        // which will not be dropped by the compiler.
-        let (value, dropfn) = unsafe { (ptr::read(&*self.value), ptr::read(&*self.dropfn)) };
-        if S::should_run() {
-            dropfn(value);
-        }
+/// A strategy selected at runtime, for example from configuration or a
+/// function argument.
+///
+/// Each variant behaves like the strategy type of the same name.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::RuntimeStrategy;
+///
+/// fn with_temp_dir(keep_on_failure: bool) {
+///     let strategy = if keep_on_failure {
+///         RuntimeStrategy::OnSuccess
+///     } else {
+///         RuntimeStrategy::Always
+///     };
+///     let _dir = scopeguard::guard_dyn_strategy("/tmp/build", |_dir| {
+///         // std::fs::remove_dir_all(dir)
+///     }, strategy);
+/// }
+/// # fn main() {
+/// #     with_temp_dir(true);
+/// # }
+/// ```
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum RuntimeStrategy {
+    /// See [`Always`](struct.Always.html).
+    Always,
+    /// See [`OnSuccess`](struct.OnSuccess.html).
+    OnSuccess,
+    /// See [`OnUnwind`](struct.OnUnwind.html).
+    OnUnwind,
+    /// See [`Never`](struct.Never.html).
+    Never,
+}
+
+impl Default for RuntimeStrategy {
+    #[inline]
+    fn default() -> Self {
+        RuntimeStrategy::Always
     }
 }
 
-impl<T, F, S> fmt::Debug for ScopeGuard<T, F, S>
-where
-    T: fmt::Debug,
-    F: FnOnce(T),
-    S: Strategy,
-{
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_struct(stringify!(ScopeGuard))
-            .field("value", &*self.value)
-            .finish()
+impl Strategy for RuntimeStrategy {
+    #[inline]
+    fn should_run(&self) -> bool {
+        match *self {
+            RuntimeStrategy::Always => Always.should_run(),
+            RuntimeStrategy::OnSuccess => OnSuccess.should_run(),
+            RuntimeStrategy::OnUnwind => OnUnwind.should_run(),
+            RuntimeStrategy::Never => Never.should_run(),
+        }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::cell::Cell;
-    use std::panic::catch_unwind;
-    use std::panic::AssertUnwindSafe;
+/// Run according to the environment variable `SCOPEGUARD_CLEANUP`, or
+/// according to the strategy `S` if it is not set.
+///
+/// The variable is read once, the first time a guard with this strategy is
+/// dropped. It takes these values:
+///
+/// - `skip`: the closures of all such guards do not run. Use it to observe the
+///   state a process leaves behind when debugging a crash.
+/// - `force`: the closures of all such guards run, regardless of `S`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::{guard_with, EnvOverride};
+///
+/// fn main() {
+///     let _tmp = guard_with::<EnvOverride, _, _>("/tmp/build", |_dir| {
+///         // std::fs::remove_dir_all(dir)
+///     });
+/// }
+/// ```
+///
+/// Requires crate feature `use_std`.
+#[cfg(feature = "use_std")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct EnvOverride<S = Always>(pub S);
+
+/// Parse the value of `SCOPEGUARD_CLEANUP` into the state stored by
+/// `EnvOverride`: 1 if not set, 2 for `skip`, 3 for `force`.
+#[cfg(feature = "use_std")]
+fn parse_env_cleanup(value: Option<&std::ffi::OsStr>) -> u8 {
+    match value {
+        Some(value) if value == "skip" => 2,
+        Some(value) if value == "force" => 3,
+        _ => 1,
+    }
+}
+
+#[cfg(feature = "use_std")]
+impl<S: Strategy> Strategy for EnvOverride<S> {
+    #[inline]
+    fn should_run(&self) -> bool {
+        // 0: not read yet, 1: not set, 2: skip, 3: force.
+        static CLEANUP: std::sync::atomic::AtomicU8 = std::sync::atomic::AtomicU8::new(0);
+        let mut cleanup = CLEANUP.load(Ordering::Relaxed);
+        if cleanup == 0 {
+            cleanup = parse_env_cleanup(std::env::var_os("SCOPEGUARD_CLEANUP").as_deref());
+            CLEANUP.store(cleanup, Ordering::Relaxed);
+        }
+        match cleanup {
+            2 => false,
+            3 => true,
+            _ => self.0.should_run(),
+        }
+    }
+}
+
+/// Run if the strategy `S` would not run.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::{guard_with, Not, WhenFlag};
+///
+/// fn main() {
+///     let _g = guard_with::<Not<WhenFlag>, _, _>((), |()| println!("flag not set"));
+/// }
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Not<S>(pub S);
+
+impl<S: Strategy> Strategy for Not<S> {
+    #[inline]
+    fn should_run(&self) -> bool {
+        !self.0.should_run()
+    }
+}
+
+/// Run if both strategies `A` and `B` would run.
+///
+/// `B` is only consulted if `A` would run.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::{And, ScopeGuard, Strategy};
+///
+/// #[derive(Default)]
+/// struct DebugBuild;
+///
+/// impl Strategy for DebugBuild {
+///     fn should_run(&self) -> bool {
+///         cfg!(debug_assertions)
+///     }
+/// }
+///
+/// # #[cfg(feature = "use_std")]
+/// fn main() {
+///     use scopeguard::OnUnwind;
+///
+///     let _dump = ScopeGuard::<_, _, And<OnUnwind, DebugBuild>>::with_strategy(
+///         vec![1, 2, 3],
+///         |state| eprintln!("state at panic: {:?}", state),
+///     );
+/// }
+/// # #[cfg(not(feature = "use_std"))]
+/// # fn main() {}
+/// ```
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct And<A, B>(pub A, pub B);
+
+impl<A: Strategy, B: Strategy> Strategy for And<A, B> {
+    #[inline]
+    fn should_run(&self) -> bool {
+        self.0.should_run() && self.1.should_run()
+    }
+}
+
+/// Run if either of the strategies `A` and `B` would run.
+///
+/// `B` is only consulted if `A` would not run.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Or<A, B>(pub A, pub B);
+
+impl<A: Strategy, B: Strategy> Strategy for Or<A, B> {
+    #[inline]
+    fn should_run(&self) -> bool {
+        self.0.should_run() || self.1.should_run()
+    }
+}
+
+/// Run if the decision closure `P` returns `true`. It is called when the guard
+/// is dropped, at most once.
+///
+/// Create guards with this strategy with
+/// [`guard_with_fn`](fn.guard_with_fn.html).
+pub struct WhenFn<P>(std::cell::Cell<Option<P>>);
+
+impl<P> WhenFn<P>
+where
+    P: FnOnce() -> bool,
+{
+    /// Create a strategy that runs if `should_run` returns `true`.
+    #[inline]
+    pub const fn new(should_run: P) -> Self {
+        WhenFn(std::cell::Cell::new(Some(should_run)))
+    }
+}
+
+impl<P> Strategy for WhenFn<P>
+where
+    P: FnOnce() -> bool,
+{
+    #[inline]
+    fn should_run(&self) -> bool {
+        match self.0.take() {
+            Some(should_run) => should_run(),
+            None => false,
+        }
+    }
+}
+
+impl<P> fmt::Debug for WhenFn<P> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(WhenFn)).finish_non_exhaustive()
+    }
+}
+
+#[cfg(feature = "use_std")]
+thread_local! {
+    static ON_UNWIND_OVERRIDE: std::cell::Cell<Option<bool>> = const { std::cell::Cell::new(None) };
+    static ON_SUCCESS_OVERRIDE: std::cell::Cell<Option<bool>> = const { std::cell::Cell::new(None) };
+}
+
+#[cfg(feature = "use_std")]
+#[inline]
+fn thread_override(
+    key: &'static std::thread::LocalKey<std::cell::Cell<Option<bool>>>,
+) -> Option<bool> {
+    // The override is gone while thread-locals are being destroyed.
+    key.try_with(|o| o.get()).unwrap_or(None)
+}
+
+#[cfg(feature = "use_std")]
+impl OnUnwind {
+    /// Override, for the current thread, whether `OnUnwind` guards run.
+    ///
+    /// With `Some(run)`, guards with this strategy that are dropped on this
+    /// thread run if and only if `run` is `true`, regardless of unwinding.
+    /// `None` restores the regular behavior. Return the previous override.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::OnUnwind;
+    ///
+    /// fn main() {
+    ///     let mut ran = false;
+    ///     OnUnwind::set_thread_override(Some(true));
+    ///     drop(scopeguard::guard_on_unwind((), |_| ran = true));
+    ///     OnUnwind::set_thread_override(None);
+    ///     assert!(ran);
+    /// }
+    /// ```
+    pub fn set_thread_override(run: Option<bool>) -> Option<bool> {
+        ON_UNWIND_OVERRIDE.with(|o| o.replace(run))
+    }
+}
+
+#[cfg(feature = "use_std")]
+impl OnSuccess {
+    /// Override, for the current thread, whether `OnSuccess` guards run.
+    ///
+    /// With `Some(run)`, guards with this strategy that are dropped on this
+    /// thread run if and only if `run` is `true`, regardless of unwinding.
+    /// `None` restores the regular behavior. Return the previous override.
+    ///
+    /// For example, a replay or sandbox thread can disable all `OnSuccess`
+    /// guards with `OnSuccess::set_thread_override(Some(false))`.
+    pub fn set_thread_override(run: Option<bool>) -> Option<bool> {
+        ON_SUCCESS_OVERRIDE.with(|o| o.replace(run))
+    }
+}
+
+/// Macro to create a `ScopeGuard` (always run).
+///
+/// The macro takes statements, which are the body of a closure
+/// that will run when the scope is exited.
+///
+/// With the form `defer!(try { ... } catch |e| ...)`, the `?` operator can be
+/// used in the deferred statements. If they fail, the error is bound to the
+/// pattern and the handler runs. Annotate the error type, as in
+/// `|e: io::Error|`, when the handler doesn't determine it.
+///
+/// ```
+/// #[macro_use(defer)]
+/// extern crate scopeguard;
+///
+/// use std::fs;
+/// use std::io;
+///
+/// fn main() {
+///     let path = std::env::temp_dir().join("scopeguard-defer-try-example");
+///     defer!(try {
+///         fs::remove_file(&path)?;
+///     } catch |e: io::Error| eprintln!("cleanup failed: {}", e));
+/// }
+/// ```
+///
+/// With the form `defer!(name: { ... })`, the guard is bound to `name` instead
+/// of a hidden variable, so that it can be cancelled, for example with
+/// `ScopeGuard::cancel(name)` on the success path.
+///
+/// ```
+/// #[macro_use(defer)]
+/// extern crate scopeguard;
+///
+/// use scopeguard::ScopeGuard;
+///
+/// fn main() {
+///     let mut staged = vec!["a.txt"];
+///     {
+///         let staged = &mut staged;
+///         defer!(rollback: {
+///             staged.clear();
+///         });
+///         // the commit succeeded
+///         ScopeGuard::cancel(rollback);
+///     }
+///     assert_eq!(staged, ["a.txt"]);
+/// }
+/// ```
+///
+/// The deferred statements capture variables of the enclosing scope by
+/// reference. With the form `defer!(move(a, b) { ... })`, the listed variables
+/// are moved into the guard instead, and the statements own them.
+///
+/// ```
+/// #[macro_use(defer)]
+/// extern crate scopeguard;
+///
+/// use std::sync::mpsc;
+///
+/// fn main() {
+///     let (done, finished) = mpsc::channel();
+///     let id = 7;
+///     {
+///         defer!(move(done) {
+///             done.send(id).unwrap();
+///         });
+///     }
+///     assert_eq!(finished.recv(), Ok(7));
+/// }
+/// ```
+///
+/// With the form `defer!(value => dropfn)`, the guard takes ownership of the
+/// variable `value` and passes it to the closure `dropfn` at scope exit. The
+/// guard shadows the variable, so the value is still reachable through it by
+/// `Deref`; write `defer!(mut value => dropfn)` for mutable access.
+///
+/// ```
+/// #[macro_use(defer)]
+/// extern crate scopeguard;
+///
+/// use std::fs::File;
+/// use std::io::Write;
+///
+/// fn main() {
+///     let path = std::env::temp_dir().join("scopeguard-defer-value-example");
+///     let file = File::create(&path).unwrap();
+///     defer!(mut file => |f: File| {
+///         let _ = f.sync_all();
+///     });
+///     file.write_all(b"test me\n").unwrap();
+/// #   std::fs::remove_file(&path).unwrap();
+/// }
+/// ```
+#[macro_export]
+macro_rules! defer {
+    (try { $($body:tt)* } catch |$err:ident $(: $err_ty:ty)?| $($handler:tt)*) => {
+        let _guard = $crate::guard((), |()| {
+            $crate::__defer_try(
+                || {
+                    { $($body)* }
+                    $crate::__defer_ok()
+                },
+                |$err $(: $err_ty)?| { $($handler)*; },
+            )
+        });
+    };
+    (move($($var:ident),* $(,)?) { $($t:tt)* }) => {
+        let _guard = $crate::guard(($($var,)*), |($($var,)*)| { $($t)* });
+    };
+    (mut $var:ident => $dropfn:expr) => {
+        let mut $var = $crate::guard($var, $dropfn);
+    };
+    ($var:ident => $dropfn:expr) => {
+        let $var = $crate::guard($var, $dropfn);
+    };
+    ($name:ident: { $($t:tt)* }) => {
+        let $name = $crate::guard((), |()| { $($t)* });
+    };
+    ($($t:tt)*) => {
+        let _guard = $crate::guard((), |()| { $($t)* });
+    };
+}
+
+#[doc(hidden)]
+pub mod __private {
+    pub use std::ops::Drop;
+}
+
+#[doc(hidden)]
+pub fn __defer_try<E>(body: impl FnOnce() -> Result<(), E>, handler: impl FnOnce(E)) {
+    if let Err(e) = body() {
+        handler(e)
+    }
+}
+
+#[doc(hidden)]
+#[inline]
+pub fn __defer_ok<E>() -> Result<(), E> {
+    Ok(())
+}
+
+/// Macro to create a `ScopeGuard` (run on successful scope exit).
+///
+/// The macro takes statements, which are the body of a closure
+/// that will run when the scope is exited.
+///
+/// Without crate feature `use_std`, unwinding is detected as described for
+/// [`set_panicking_hook`](fn.set_panicking_hook.html).
+#[macro_export]
+macro_rules! defer_on_success {
+    ($($t:tt)*) => {
+        let _guard = $crate::guard_on_success((), |()| { $($t)* });
+    };
+}
+
+/// Macro to create a `ScopeGuard` (run on unwinding from panic).
+///
+/// The macro takes statements, which are the body of a closure
+/// that will run when the scope is exited.
+///
+/// Without crate feature `use_std`, unwinding is detected as described for
+/// [`set_panicking_hook`](fn.set_panicking_hook.html).
+#[macro_export]
+macro_rules! defer_on_unwind {
+    ($($t:tt)*) => {
+        let _guard = $crate::guard_on_unwind((), |()| { $($t)* });
+    };
+}
+
+/// Macro to cancel a guard created with the named form of
+/// [`defer!`](macro.defer.html): its statements do not run.
+///
+/// `cancel_defer!(name)` is the same as `ScopeGuard::cancel(name)`.
+///
+/// ```
+/// #[macro_use(defer, cancel_defer)]
+/// extern crate scopeguard;
+///
+/// use std::cell::Cell;
+///
+/// fn transfer(balance: &Cell<i32>, amount: i32, commit: bool) {
+///     balance.set(balance.get() - amount);
+///     defer!(rollback: {
+///         balance.set(balance.get() + amount);
+///     });
+///     if commit {
+///         cancel_defer!(rollback);
+///     }
+/// }
+///
+/// fn main() {
+///     let balance = Cell::new(100);
+///     transfer(&balance, 30, false);
+///     assert_eq!(balance.get(), 100);
+///     transfer(&balance, 30, true);
+///     assert_eq!(balance.get(), 70);
+/// }
+/// ```
+#[macro_export]
+macro_rules! cancel_defer {
+    ($name:ident) => {
+        $crate::ScopeGuard::cancel($name)
+    };
+}
+
+/// Macro to create a `ScopeGuard` (always run) whose deferred statements can
+/// fail.
+///
+/// `try_defer!(policy, { statements })` defers the statements, which can use
+/// the `?` operator with any error type that converts into
+/// `Box<dyn Error + Send + Sync>`. If they fail, the error is handled
+/// according to `policy`:
+///
+/// | Policy   | Handling of the error                      | Crate features      |
+/// |----------|--------------------------------------------|---------------------|
+/// | `ignore` | discard it                                 | `use_std`           |
+/// | `eprint` | print it to stderr                         | `use_std`           |
+/// | `log`    | log it with `log::error!`                  | `use_std` and `log` |
+/// | `panic`  | panic with it; aborts if already unwinding | `use_std`           |
+///
+/// Using the `log` policy without crate feature `log`, or any other policy,
+/// is a compile error that names the missing feature or the known policies.
+///
+/// There is no `tracing` policy. To report the error with `tracing::error!`,
+/// or to handle it in some other way, use the form
+/// `defer!(try { ... } catch |e| ...)` of [`defer!`](macro.defer.html).
+///
+/// ```
+/// #[macro_use(try_defer)]
+/// extern crate scopeguard;
+///
+/// use std::fs;
+///
+/// fn main() {
+///     let dir = std::env::temp_dir().join("scopeguard-try-defer-example");
+///     fs::create_dir_all(&dir).unwrap();
+///     try_defer!(eprint, {
+///         fs::remove_file(dir.join("lock"))?;
+///         fs::remove_dir(&dir)?;
+///     });
+/// }
+/// ```
+///
+/// Requires crate feature `use_std`.
+#[cfg(feature = "use_std")]
+#[macro_export]
+macro_rules! try_defer {
+    (ignore, { $($body:tt)* }) => {
+        $crate::try_defer!(@handle $crate::__try_defer::ignore, $($body)*);
+    };
+    (eprint, { $($body:tt)* }) => {
+        $crate::try_defer!(@handle $crate::__try_defer::eprint, $($body)*);
+    };
+    (log, { $($body:tt)* }) => {
+        $crate::__try_defer_log!($($body)*);
+    };
+    (panic, { $($body:tt)* }) => {
+        $crate::try_defer!(@handle $crate::__try_defer::panic, $($body)*);
+    };
+    (@handle $handler:path, $($body:tt)*) => {
+        let _guard = $crate::guard((), |()| {
+            $crate::__defer_try(
+                || -> $crate::__try_defer::Result {
+                    { $($body)* }
+                    $crate::__defer_ok()
+                },
+                $handler,
+            )
+        });
+    };
+    ($policy:ident, { $($body:tt)* }) => {
+        compile_error!(concat!(
+            "unknown try_defer! policy `",
+            stringify!($policy),
+            "`; expected `ignore`, `eprint`, `log` or `panic`"
+        ));
+    };
+}
+
+// Without crate feature `log`, the `log` policy of `try_defer!` reports the
+// missing feature instead of an unresolved handler.
+#[cfg(all(feature = "use_std", feature = "log"))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __try_defer_log {
+    ($($body:tt)*) => {
+        $crate::try_defer!(@handle $crate::__try_defer::log, $($body)*);
+    };
+}
+
+#[cfg(all(feature = "use_std", not(feature = "log")))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __try_defer_log {
+    ($($body:tt)*) => {
+        compile_error!("the `log` policy of try_defer! requires the scopeguard feature `log`");
+    };
+}
+
+#[cfg(feature = "use_std")]
+#[doc(hidden)]
+pub mod __try_defer {
+    use std::error::Error;
+
+    pub type Result = std::result::Result<(), Box<dyn Error + Send + Sync>>;
+
+    pub fn ignore(_: Box<dyn Error + Send + Sync>) {}
+
+    pub fn eprint(e: Box<dyn Error + Send + Sync>) {
+        eprintln!("deferred cleanup failed: {}", e);
+    }
+
+    #[cfg(feature = "log")]
+    pub fn log(e: Box<dyn Error + Send + Sync>) {
+        ::log::error!("deferred cleanup failed: {}", e);
+    }
+
+    pub fn panic(e: Box<dyn Error + Send + Sync>) {
+        panic!("deferred cleanup failed: {}", e);
+    }
+}
+
+// Without an executor, `defer_async!` reports the missing feature instead of
+// an unresolved `spawn` module.
+#[cfg(not(any(
+    feature = "tokio",
+    feature = "async-std",
+    feature = "smol",
+    all(feature = "wasm", target_arch = "wasm32")
+)))]
+#[doc(hidden)]
+#[macro_export]
+macro_rules! defer_async {
+    ($($t:tt)*) => {
+        compile_error!(
+            "defer_async! requires one of the scopeguard features `tokio`, `async-std`, `smol` or `wasm`"
+        );
+    };
+}
+
+/// Macro to declare several named guards (always run) at once.
+///
+/// Each entry `name = (value, dropfn);` expands to
+/// `let name = scopeguard::guard(value, dropfn);`. The name is a pattern, so
+/// `mut name` makes the guarded value mutable. Like separate `let`
+/// statements, the guards are dropped in reverse order of declaration.
+///
+/// ```
+/// #[macro_use(guards)]
+/// extern crate scopeguard;
+///
+/// use std::cell::RefCell;
+///
+/// fn main() {
+///     let log = RefCell::new(Vec::new());
+///     {
+///         guards! {
+///             mut buffer = (Vec::new(), |b: Vec<u8>| log.borrow_mut().push(b.len()));
+///             _cleanup = ((), |()| log.borrow_mut().push(0));
+///         }
+///         buffer.push(1);
+///     }
+///     assert_eq!(*log.borrow(), [0, 1]);
+/// }
+/// ```
+#[macro_export]
+macro_rules! guards {
+    ($($name:pat = ($v:expr, $dropfn:expr));* $(;)?) => {
+        $(
+            let $name = $crate::guard($v, $dropfn);
+        )*
+    };
+}
+
+/// Macro for an expression that evaluates a body and then runs a cleanup,
+/// like `try`/`finally` in other languages.
+///
+/// `finally!({ body } cleanup { statements })` evaluates to the value of the
+/// body. The cleanup statements run after it, also when the body panics or
+/// leaves through `return`, `break` or `?`, which apply to the enclosing
+/// function or loop.
+///
+/// ```
+/// #[macro_use(finally)]
+/// extern crate scopeguard;
+///
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+///
+/// static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+///
+/// fn main() {
+///     IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+///     let sum = finally!({
+///         (1..=10).sum::<u32>()
+///     } cleanup {
+///         IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+///     });
+///     assert_eq!(sum, 55);
+///     assert_eq!(IN_FLIGHT.load(Ordering::SeqCst), 0);
+/// }
+/// ```
+#[macro_export]
+macro_rules! finally {
+    ({ $($body:tt)* } cleanup { $($cleanup:tt)* }) => {{
+        let _guard = $crate::guard((), |()| { $($cleanup)* });
+        { $($body)* }
+    }};
+}
+
+/// Macro to create a `ScopeGuard` with the strategy named by a keyword.
+///
+/// `guard!(strategy, value, dropfn)` expands to the constructor for the
+/// strategy, where `strategy` is one of `always`, `on_success`, `on_unwind`
+/// and `never`. Without the strategy, `guard!(value, dropfn)` is the same as
+/// `guard!(always, value, dropfn)`. This is convenient in macro-generated
+/// code, which can forward the keyword.
+///
+/// ```
+/// #[macro_use(guard)]
+/// extern crate scopeguard;
+///
+/// fn main() {
+///     let mut journal = guard!(on_unwind, Vec::new(), |entries: Vec<&str>| {
+///         for entry in entries.iter().rev() {
+///             println!("rolling back {}", entry);
+///         }
+///     });
+///     journal.push("insert");
+/// }
+/// ```
+#[macro_export]
+macro_rules! guard {
+    (always, $v:expr, $dropfn:expr $(,)?) => {
+        $crate::guard($v, $dropfn)
+    };
+    (on_success, $v:expr, $dropfn:expr $(,)?) => {
+        $crate::guard_on_success($v, $dropfn)
+    };
+    (on_unwind, $v:expr, $dropfn:expr $(,)?) => {
+        $crate::guard_on_unwind($v, $dropfn)
+    };
+    (never, $v:expr, $dropfn:expr $(,)?) => {
+        $crate::guard_with::<$crate::Never, _, _>($v, $dropfn)
+    };
+    ($v:expr, $dropfn:expr $(,)?) => {
+        $crate::guard($v, $dropfn)
+    };
+}
+
+/// Macro for a block with deferred cleanups, which run in reverse order at the
+/// end of the block.
+///
+/// Inside the block, each `defer { ... }` registers its statements as a
+/// cleanup, like [`defer!`](macro.defer.html). The other statements are
+/// unchanged, and the block evaluates to its final expression, if any.
+///
+/// ```
+/// #[macro_use(scope)]
+/// extern crate scopeguard;
+///
+/// use std::cell::RefCell;
+///
+/// fn main() {
+///     let log = RefCell::new(Vec::new());
+///     let total = scope! {
+///         log.borrow_mut().push("open a");
+///         defer { log.borrow_mut().push("close a"); }
+///         log.borrow_mut().push("open b");
+///         defer { log.borrow_mut().push("close b"); }
+///         1 + 2
+///     };
+///     assert_eq!(total, 3);
+///     assert_eq!(*log.borrow(), ["open a", "open b", "close b", "close a"]);
+/// }
+/// ```
+///
+/// `defer` is only recognized in statement position at the top level of the
+/// block, not inside nested blocks.
+#[macro_export]
+macro_rules! scope {
+    (@munch [$($done:tt)*]) => {
+        { $($done)* }
+    };
+    (@munch [$($done:tt)*] defer { $($cleanup:tt)* } ; $($rest:tt)*) => {
+        $crate::scope!(@munch [
+            $($done)*
+            let _guard = $crate::guard((), |()| { $($cleanup)* });
+        ] $($rest)*)
+    };
+    (@munch [$($done:tt)*] defer { $($cleanup:tt)* } $($rest:tt)*) => {
+        $crate::scope!(@munch [
+            $($done)*
+            let _guard = $crate::guard((), |()| { $($cleanup)* });
+        ] $($rest)*)
+    };
+    (@munch [$($done:tt)*] ; $($rest:tt)*) => {
+        $crate::scope!(@munch [$($done)*] $($rest)*)
+    };
+    // One statement per step, so that long blocks stay far below the
+    // recursion limit.
+    (@munch [$($done:tt)*] let $($rest:tt)*) => {
+        $crate::scope!(@let [$($done)*] let $($rest)*)
+    };
+    // Statements that end with a block, like `if` and `for`, need no `;`.
+    (@munch [$($done:tt)*] $label:lifetime : $($rest:tt)*) => {
+        $crate::scope!(@block [$($done)*] [$label :] $($rest)*)
+    };
+    (@munch [$($done:tt)*] if $($rest:tt)*) => {
+        $crate::scope!(@block [$($done)*] [if] $($rest)*)
+    };
+    (@munch [$($done:tt)*] match $($rest:tt)*) => {
+        $crate::scope!(@block [$($done)*] [match] $($rest)*)
+    };
+    (@munch [$($done:tt)*] while $($rest:tt)*) => {
+        $crate::scope!(@block [$($done)*] [while] $($rest)*)
+    };
+    (@munch [$($done:tt)*] for $($rest:tt)*) => {
+        $crate::scope!(@block [$($done)*] [for] $($rest)*)
+    };
+    (@munch [$($done:tt)*] loop $($rest:tt)*) => {
+        $crate::scope!(@block [$($done)*] [loop] $($rest)*)
+    };
+    (@munch [$($done:tt)*] unsafe { $($block:tt)* } $($rest:tt)*) => {
+        $crate::scope!(@munch [$($done)* unsafe { $($block)* }] $($rest)*)
+    };
+    (@munch [$($done:tt)*] { $($block:tt)* } $($rest:tt)*) => {
+        $crate::scope!(@munch [$($done)* { $($block)* }] $($rest)*)
+    };
+    (@munch [$($done:tt)*] $name:ident ! { $($args:tt)* } $($rest:tt)*) => {
+        $crate::scope!(@munch [$($done)* $name! { $($args)* }] $($rest)*)
+    };
+    // Items, which end with a block or include their `;`.
+    (@munch [$($done:tt)*] fn $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] fn $($rest)*)
+    };
+    (@munch [$($done:tt)*] struct $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] struct $($rest)*)
+    };
+    (@munch [$($done:tt)*] enum $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] enum $($rest)*)
+    };
+    (@munch [$($done:tt)*] impl $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] impl $($rest)*)
+    };
+    (@munch [$($done:tt)*] trait $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] trait $($rest)*)
+    };
+    (@munch [$($done:tt)*] mod $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] mod $($rest)*)
+    };
+    (@munch [$($done:tt)*] const $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] const $($rest)*)
+    };
+    (@munch [$($done:tt)*] static $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] static $($rest)*)
+    };
+    (@munch [$($done:tt)*] use $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] use $($rest)*)
+    };
+    (@munch [$($done:tt)*] type $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] type $($rest)*)
+    };
+    (@munch [$($done:tt)*] unsafe $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] unsafe $($rest)*)
+    };
+    (@munch [$($done:tt)*] extern $($rest:tt)*) => {
+        $crate::scope!(@item [$($done)*] extern $($rest)*)
+    };
+    // Expression statements.
+    (@munch [$($done:tt)*] $expr:expr ; $($rest:tt)*) => {
+        $crate::scope!(@munch [$($done)* $expr;] $($rest)*)
+    };
+    // The final expression of the block.
+    (@munch [$($done:tt)*] $($tail:tt)+) => {
+        { $($done)* $($tail)+ }
+    };
+    // A `let` statement already includes its `;`.
+    (@let [$($done:tt)*] $stmt:stmt ; $($rest:tt)*) => {
+        $crate::scope!(@munch [$($done)* $stmt] $($rest)*)
+    };
+    (@item [$($done:tt)*] $item:item $($rest:tt)*) => {
+        $crate::scope!(@munch [$($done)* $item] $($rest)*)
+    };
+    // The head of a statement that ends with a block, up to the block and any
+    // `else` branches.
+    (@block [$($done:tt)*] [$($head:tt)*] { $($body:tt)* } else if $($rest:tt)*) => {
+        $crate::scope!(@block [$($done)*] [$($head)* { $($body)* } else if] $($rest)*)
+    };
+    (@block [$($done:tt)*] [$($head:tt)*] { $($body:tt)* } else { $($other:tt)* } $($rest:tt)*) => {
+        $crate::scope!(@munch [$($done)* $($head)* { $($body)* } else { $($other)* }] $($rest)*)
+    };
+    (@block [$($done:tt)*] [$($head:tt)*] { $($body:tt)* } $($rest:tt)*) => {
+        $crate::scope!(@munch [$($done)* $($head)* { $($body)* }] $($rest)*)
+    };
+    (@block [$($done:tt)*] [$($head:tt)*] $next:tt $($rest:tt)*) => {
+        $crate::scope!(@block [$($done)*] [$($head)* $next] $($rest)*)
+    };
+    ($($body:tt)*) => {
+        $crate::scope!(@munch [] $($body)*)
+    };
+}
+
+/// `ScopeGuard` is a scope guard that may own a protected value.
+///
+/// If you place a guard in a local variable, the closure can
+/// run regardless how you leave the scope — through regular return or panic
+/// (except if panic or other code aborts; so as long as destructors run).
+/// It is run only once.
+///
+/// The `S` parameter for [`Strategy`](trait.Strategy.html) determines if
+/// the closure actually runs.
+///
+/// The guard's closure will be called with the held value in the destructor.
+///
+/// Bind the guard to a named variable, like `let _guard = guard(...)`, to keep
+/// it alive until the end of the scope. An unused guard and `let _ = guard(...)`
+/// both drop it, and run the closure, immediately; the compiler warns about the
+/// former but not the latter.
+///
+/// The `ScopeGuard` implements `Deref` so that you can access the inner value.
+///
+/// All other operations on the guard are associated functions, such as
+/// `ScopeGuard::into_inner(guard)`, so that they never shadow methods of `T`
+/// reached through `Deref`. In generic or macro-generated code, where it is
+/// not known which methods `T` has, use
+/// [`ScopeGuard::get_ref`](#method.get_ref) and
+/// [`ScopeGuard::get_mut`](#method.get_mut) to access the value
+/// unambiguously.
+#[must_use = "if unused the guard runs its closure immediately; bind it to a named variable, like `let _guard = ...`, to keep it until the end of the scope"]
+pub struct ScopeGuard<T, F, S = Always>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    value: ManuallyDrop<T>,
+    dropfn: ManuallyDrop<F>,
+    // Cleared by `disarm`.
+    armed: bool,
+    strategy: S,
+    // Set through `Builder`, only kept with crate feature `debug_info`.
+    #[cfg(feature = "debug_info")]
+    info: GuardInfo,
+}
+
+#[cfg(feature = "debug_info")]
+#[derive(Copy, Clone, Default)]
+struct GuardInfo {
+    name: Option<&'static str>,
+    location: Option<&'static Location<'static>>,
+}
+
+#[cfg(feature = "debug_info")]
+impl GuardInfo {
+    const NONE: GuardInfo = GuardInfo {
+        name: None,
+        location: None,
+    };
+}
+
+impl<T, F, S> ScopeGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    /// Create a `ScopeGuard` that owns `v` (accessible through deref) and calls
+    /// `dropfn` when its destructor runs, if `strategy` says so.
+    ///
+    /// This is a `const fn`, so guards can be created in constants and
+    /// `static` initializers.
+    #[inline]
+    pub const fn new(v: T, dropfn: F, strategy: S) -> ScopeGuard<T, F, S> {
+        ScopeGuard {
+            value: ManuallyDrop::new(v),
+            dropfn: ManuallyDrop::new(dropfn),
+            armed: true,
+            strategy,
+            #[cfg(feature = "debug_info")]
+            info: GuardInfo::NONE,
+        }
+    }
+
+    /// Create a `ScopeGuard` that owns `v` (accessible through deref) and calls
+    /// `dropfn` when its destructor runs.
+    ///
+    /// The `Strategy` decides whether the scope guard's closure should run. It
+    /// is created with `Default`; use [`new`](#method.new) to pass a strategy
+    /// with state.
+    #[inline]
+    pub fn with_strategy(v: T, dropfn: F) -> ScopeGuard<T, F, S>
+    where
+        S: Default,
+    {
+        ScopeGuard::new(v, dropfn, S::default())
+    }
+
+    /// Return a reference to the guard's strategy.
+    #[inline]
+    pub fn strategy(guard: &Self) -> &S {
+        &guard.strategy
+    }
+
+    /// Return a mutable reference to the guard's strategy, to change its
+    /// state.
+    #[inline]
+    pub fn strategy_mut(guard: &mut Self) -> &mut S {
+        &mut guard.strategy
+    }
+
+    /// “Defuse” the guard and extract the value without calling the closure.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn conditional() -> bool { true }
+    ///
+    /// fn main() {
+    ///     let mut guard = guard(Vec::new(), |mut v| v.clear());
+    ///     guard.push(1);
+    ///
+    ///     if conditional() {
+    ///         // a condition maybe makes us decide to
+    ///         // “defuse” the guard and get back its inner parts
+    ///         let value = ScopeGuard::into_inner(guard);
+    ///     } else {
+    ///         // guard still exists in this branch
+    ///     }
+    /// }
+    /// ```
+    #[inline]
+    pub fn into_inner(guard: Self) -> T {
+        // Drop the closure after `value` has been read, so that if the
+        // closure's `drop` function panics, unwinding still tries to drop
+        // `value`.
+        let (value, dropfn, strategy) = ScopeGuard::into_raw_parts(guard);
+        drop(dropfn);
+        drop(strategy);
+        value
+    }
+
+    /// “Defuse” the guard and extract both the value and the closure, without
+    /// calling the closure.
+    ///
+    /// This is useful when the closure owns resources, like a buffer or a
+    /// connection, that should be reused.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let log = Vec::<String>::new();
+    ///     let guard = guard(5, move |n| {
+    ///         let mut log = log;
+    ///         log.push(format!("cleaned up {}", n));
+    ///     });
+    ///     let (value, dropfn) = ScopeGuard::into_parts(guard);
+    ///     assert_eq!(value, 5);
+    ///     // the closure can still be called, or used in a new guard
+    ///     dropfn(value);
+    /// }
+    /// ```
+    #[inline]
+    pub fn into_parts(guard: Self) -> (T, F) {
+        let (value, dropfn, _) = ScopeGuard::into_raw_parts(guard);
+        (value, dropfn)
+    }
+
+    fn into_raw_parts(guard: Self) -> (T, F, S) {
+        // Cannot move out of `Drop`-implementing types,
+        // so take the fields out and forget the guard.
+        let mut guard = ManuallyDrop::new(guard);
+        // Each field is taken exactly once, and the guard is not dropped.
+        unsafe {
+            (
+                ManuallyDrop::take(&mut guard.value),
+                ManuallyDrop::take(&mut guard.dropfn),
+                ptr::read(&guard.strategy),
+            )
+        }
+    }
+
+    /// “Defuse” the guard and drop the value normally, without calling the
+    /// closure.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let temp = guard(String::from("scratch"), |_| panic!("not reached"));
+    ///     // the work succeeded: keep the file, no cleanup needed
+    ///     ScopeGuard::cancel(temp);
+    /// }
+    /// ```
+    #[inline]
+    pub fn cancel(guard: Self) {
+        drop(ScopeGuard::into_inner(guard));
+    }
+
+    /// Leak the value and the closure: neither the closure nor any
+    /// destructor runs.
+    ///
+    /// This is like `std::mem::forget` for both parts of the guard. It is
+    /// safe, but resources owned by the value or the closure are never
+    /// released.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let v = guard(vec![1, 2, 3], |_| panic!("not reached"));
+    ///     ScopeGuard::forget(v);
+    /// }
+    /// ```
+    #[inline]
+    pub fn forget(guard: Self) {
+        std::mem::forget(guard);
+    }
+
+    /// Consume the guard and call the closure with the value now, regardless
+    /// of the strategy.
+    ///
+    /// A [disarmed](#method.disarm) guard does not call the closure, like when
+    /// it is dropped; the value is dropped instead.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use std::cell::Cell;
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let synced = Cell::new(false);
+    ///     let file = guard("data.txt", |_| synced.set(true));
+    ///     // finished early: sync now rather than at the end of the scope
+    ///     ScopeGuard::run_now(file);
+    ///     assert!(synced.get());
+    /// }
+    /// ```
+    #[inline]
+    pub fn run_now(guard: Self) {
+        let armed = guard.armed;
+        let (value, dropfn) = ScopeGuard::into_parts(guard);
+        if armed {
+            dropfn(value)
+        }
+    }
+
+    /// Disarm the guard in place: the closure will not run when it is
+    /// dropped, unless the guard is rearmed.
+    ///
+    /// Unlike [`into_inner`](#method.into_inner), this does not consume the
+    /// guard, so it works for guards stored in struct fields or behind a
+    /// `&mut`. The value is still dropped normally.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let mut rollback = guard((), |()| panic!("not reached"));
+    ///     ScopeGuard::disarm(&mut rollback);
+    ///     assert!(!ScopeGuard::is_armed(&rollback));
+    /// }
+    /// ```
+    #[inline]
+    pub fn disarm(guard: &mut Self) {
+        guard.armed = false;
+    }
+
+    /// Rearm a guard disarmed with [`disarm`](#method.disarm).
+    #[inline]
+    pub fn rearm(guard: &mut Self) {
+        guard.armed = true;
+    }
+
+    /// Return `true` if the guard is armed, which it is unless it was
+    /// disarmed with [`disarm`](#method.disarm).
+    #[inline]
+    pub fn is_armed(guard: &Self) -> bool {
+        guard.armed
+    }
+
+    /// Return a reference to the protected value, without going through
+    /// `Deref`.
+    ///
+    /// This is an associated function, so that it can never shadow a method
+    /// of the same name on `T`; call it as `ScopeGuard::get_ref(&guard)`.
+    #[inline]
+    pub fn get_ref(guard: &Self) -> &T {
+        &guard.value
+    }
+
+    /// Return a mutable reference to the protected value, without going
+    /// through `DerefMut`.
+    ///
+    /// This is an associated function, so that it can never shadow a method
+    /// of the same name on `T`; call it as `ScopeGuard::get_mut(&mut guard)`.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use std::io::{BufReader, Read};
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let mut reader = guard(BufReader::new(&b"data"[..]), |_| {});
+    ///     // `reader.get_mut()` would call `BufReader::get_mut` through deref
+    ///     let _inner: &mut &[u8] = reader.get_mut();
+    ///     let buffered: &mut BufReader<&[u8]> = ScopeGuard::get_mut(&mut reader);
+    ///     let mut s = String::new();
+    ///     buffered.read_to_string(&mut s).unwrap();
+    ///     assert_eq!(s, "data");
+    /// }
+    /// ```
+    #[inline]
+    pub fn get_mut(guard: &mut Self) -> &mut T {
+        &mut guard.value
+    }
+
+    /// Return a reference to the guard's closure, to inspect its captured
+    /// state.
+    ///
+    /// The closure must be `Sync`, because the guard is `Sync` even if its
+    /// closure isn't.
+    #[inline]
+    pub fn closure_ref(guard: &Self) -> &F
+    where
+        F: Sync,
+    {
+        &guard.dropfn
+    }
+
+    /// Return a mutable reference to the guard's closure, to change its
+    /// state after the guard was created.
+    ///
+    /// A closure's captures can't be named from outside, so this is mostly
+    /// useful with function pointers, boxed closures or a custom type that
+    /// holds the state and is called from the closure.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use std::fs;
+    /// use std::path::PathBuf;
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn remove(path: PathBuf) {
+    ///     let _ = fs::remove_file(path);
+    /// }
+    ///
+    /// fn keep(_: PathBuf) {}
+    ///
+    /// fn main() {
+    ///     let mut output = guard(PathBuf::from("output.tmp"), remove as fn(PathBuf));
+    ///     // ... the output turned out fine, keep the file.
+    ///     *ScopeGuard::closure_mut(&mut output) = keep;
+    /// }
+    /// ```
+    #[inline]
+    pub fn closure_mut(guard: &mut Self) -> &mut F {
+        &mut guard.dropfn
+    }
+
+    /// Project a pinned guard to its pinned protected value.
+    ///
+    /// The guard pins the value structurally: it never moves the value while
+    /// it is pinned, and when the closure does not run (because of the
+    /// strategy or [`disarm`](#method.disarm)), the value is dropped in place.
+    /// `into_inner` and the other functions that move the value need an
+    /// unpinned guard.
+    ///
+    /// # Safety
+    ///
+    /// When the closure runs, it receives the value by value, so the value is
+    /// moved out of the pinned guard. The caller must ensure that this is
+    /// sound for `T`: either the closure never runs while the value is pinned,
+    /// or nothing relies on the value's address any more by then, for example
+    /// because the pinned future has completed or the intrusive node was
+    /// unlinked.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use std::future::Future;
+    /// use std::pin::Pin;
+    /// use std::task::{Context, Poll};
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn poll_guarded<Fut, F>(
+    ///     fut: Pin<&mut ScopeGuard<Fut, F>>,
+    ///     cx: &mut Context,
+    /// ) -> Poll<Fut::Output>
+    /// where
+    ///     Fut: Future,
+    ///     F: FnOnce(Fut),
+    /// {
+    ///     // The closure only looks at the future, it doesn't rely on it
+    ///     // staying in place.
+    ///     unsafe { ScopeGuard::as_pinned_mut(fut) }.poll(cx)
+    /// }
+    /// # fn main() {
+    /// #     let _ = poll_guarded::<std::future::Ready<()>, fn(_)>;
+    /// # }
+    /// ```
+    #[inline]
+    pub unsafe fn as_pinned_mut(guard: Pin<&mut Self>) -> Pin<&mut T> {
+        guard.map_unchecked_mut(|guard| &mut *guard.value)
+    }
+
+    /// Transform the protected value with `f`, keeping the guard armed.
+    ///
+    /// `dropfn` receives the current closure and returns the closure of the
+    /// new guard. It can wrap the current closure, converting the new value
+    /// back first, or discard it and return a new one.
+    ///
+    /// If `f` panics, the value is lost and no closure runs.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use std::io::{Cursor, Write};
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let buf = guard(Vec::new(), |buf: Vec<u8>| assert_eq!(buf, b"header"));
+    ///     let mut cursor = ScopeGuard::map(buf, Cursor::new, |dropfn| {
+    ///         move |cursor: Cursor<Vec<u8>>| dropfn(cursor.into_inner())
+    ///     });
+    ///     cursor.write_all(b"header").unwrap();
+    /// }
+    /// ```
+    #[inline]
+    pub fn map<U, M, D, G>(guard: Self, f: M, dropfn: D) -> ScopeGuard<U, G, S>
+    where
+        M: FnOnce(T) -> U,
+        D: FnOnce(F) -> G,
+        G: FnOnce(U),
+    {
+        ScopeGuard::rebuild(guard, |v, old, strategy| (f(v), dropfn(old), strategy))
+    }
+
+    /// Add the cleanup step `extra`, which runs before the current closure.
+    ///
+    /// This suits a resource acquired later in the scope that depends on the
+    /// protected value: like nested guards, the later cleanup runs first. If
+    /// `extra` panics, the current closure still runs.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use std::cell::RefCell;
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let log = RefCell::new(Vec::new());
+    ///     {
+    ///         let conn = guard("conn", |_| log.borrow_mut().push("close connection"));
+    ///         // ... later, a transaction is opened on the connection
+    ///         let _conn = ScopeGuard::and_then(conn, |_| log.borrow_mut().push("rollback"));
+    ///     }
+    ///     assert_eq!(*log.borrow(), ["rollback", "close connection"]);
+    /// }
+    /// ```
+    #[inline]
+    pub fn and_then<E>(guard: Self, extra: E) -> ScopeGuard<T, impl FnOnce(T), S>
+    where
+        E: FnOnce(&mut T),
+    {
+        ScopeGuard::rebuild(guard, |v, dropfn, strategy| {
+            let dropfn = move |v| {
+                let mut v = ::guard(v, dropfn);
+                extra(&mut *v);
+            };
+            (v, dropfn, strategy)
+        })
+    }
+
+    /// Replace the protected value with `value`, and return the previous
+    /// value. The closure stays armed and will receive the new value.
+    ///
+    /// This is an associated function; call it as
+    /// `ScopeGuard::replace(&mut guard, value)`.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let mut batch = guard(vec![1, 2], |b| println!("flushing {:?}", b));
+    ///     let full = ScopeGuard::replace(&mut batch, Vec::with_capacity(16));
+    ///     assert_eq!(full, [1, 2]);
+    ///     assert!(batch.is_empty());
+    /// }
+    /// ```
+    #[inline]
+    pub fn replace(guard: &mut Self, value: T) -> T {
+        std::mem::replace(&mut *guard.value, value)
+    }
+
+    /// Take the protected value, leaving `T::default()` in its place. The
+    /// closure stays armed and will receive the default value, or whatever
+    /// the guard holds when it is dropped.
+    ///
+    /// This is an associated function; call it as
+    /// `ScopeGuard::take(&mut guard)`.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     let mut pending = guard(Vec::new(), |rest: Vec<u32>| assert_eq!(rest, [3]));
+    ///     pending.extend(&[1, 2]);
+    ///     let drained = ScopeGuard::take(&mut pending);
+    ///     assert_eq!(drained, [1, 2]);
+    ///     pending.push(3);
+    /// }
+    /// ```
+    #[inline]
+    pub fn take(guard: &mut Self) -> T
+    where
+        T: Default,
+    {
+        ScopeGuard::replace(guard, T::default())
+    }
+
+    /// Swap the protected value with `other`. The closure stays armed and
+    /// will receive the value swapped in.
+    ///
+    /// This is an associated function; call it as
+    /// `ScopeGuard::swap(&mut guard, &mut other)`.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// fn main() {
+    ///     // double buffering: the guard always holds the buffer to flush
+    ///     let mut front = Vec::new();
+    ///     let mut dirty = guard(Vec::new(), |b: Vec<u8>| assert_eq!(b, b"2"));
+    ///     dirty.push(b'1');
+    ///     ScopeGuard::swap(&mut dirty, &mut front);
+    ///     dirty.push(b'2');
+    ///     assert_eq!(front, b"1");
+    /// }
+    /// ```
+    #[inline]
+    pub fn swap(guard: &mut Self, other: &mut T) {
+        std::mem::swap(&mut *guard.value, other)
+    }
+
+    /// Convert the guard to use the strategy `S2`, keeping the value and the
+    /// closure.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, ScopeGuard};
+    ///
+    /// # #[cfg(feature = "use_std")]
+    /// fn main() {
+    ///     let setup = guard(vec![1], |v| println!("undoing {:?}", v));
+    ///     // setup is done: from now on, only undo if we panic
+    ///     let _undo = ScopeGuard::into_strategy::<scopeguard::OnUnwind>(setup);
+    /// }
+    /// # #[cfg(not(feature = "use_std"))]
+    /// # fn main() {}
+    /// ```
+    #[inline]
+    pub fn into_strategy<S2>(guard: Self) -> ScopeGuard<T, F, S2>
+    where
+        S2: Strategy + Default,
+    {
+        ScopeGuard::rebuild(guard, |v, dropfn, _| (v, dropfn, S2::default()))
+    }
+
+    /// Convert the guard into a [`BoxedGuard`](type.BoxedGuard.html), which
+    /// erases the type of its closure.
+    ///
+    /// Requires crate feature `alloc`.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::{guard, BoxedGuard, ScopeGuard};
+    ///
+    /// struct Connection {
+    ///     cleanup: Option<BoxedGuard<'static, u32>>,
+    /// }
+    ///
+    /// fn main() {
+    ///     let conn = Connection {
+    ///         cleanup: Some(ScopeGuard::into_boxed(guard(7, |id| println!("closing {}", id)))),
+    ///     };
+    ///     assert_eq!(conn.cleanup.as_deref(), Some(&7));
+    /// }
+    /// ```
+    #[cfg(feature = "alloc")]
+    #[inline]
+    pub fn into_boxed<'a>(guard: Self) -> BoxedGuard<'a, T, S>
+    where
+        F: Send + 'a,
+    {
+        ScopeGuard::rebuild(guard, |v, dropfn, strategy| {
+            (v, Box::new(dropfn) as Box<_>, strategy)
+        })
+    }
+
+    /// Convert the guard into a [`LocalBoxedGuard`](type.LocalBoxedGuard.html),
+    /// which erases the type of its closure. The closure does not need to be
+    /// `Send`.
+    ///
+    /// Requires crate feature `alloc`.
+    #[cfg(feature = "alloc")]
+    #[inline]
+    pub fn into_local_boxed<'a>(guard: Self) -> LocalBoxedGuard<'a, T, S>
+    where
+        F: 'a,
+    {
+        ScopeGuard::rebuild(guard, |v, dropfn, strategy| {
+            (v, Box::new(dropfn) as Box<_>, strategy)
+        })
+    }
+
+    /// Convert the guard into a [`BoxedGuard`](type.BoxedGuard.html) whose
+    /// closure is boxed in the allocator `alloc`.
+    ///
+    /// Requires crate feature `allocator_api`.
+    #[cfg(feature = "allocator_api")]
+    #[inline]
+    pub fn into_boxed_in<'a, A>(guard: Self, alloc: A) -> BoxedGuard<'a, T, S, A>
+    where
+        F: Send + 'a,
+        A: Allocator,
+    {
+        ScopeGuard::rebuild(guard, |v, dropfn, strategy| {
+            (v, Box::new_in(dropfn, alloc) as Box<_, A>, strategy)
+        })
+    }
+
+    /// Convert the guard into a
+    /// [`LocalBoxedGuard`](type.LocalBoxedGuard.html) whose closure is boxed
+    /// in the allocator `alloc`.
+    ///
+    /// Requires crate feature `allocator_api`.
+    #[cfg(feature = "allocator_api")]
+    #[inline]
+    pub fn into_local_boxed_in<'a, A>(guard: Self, alloc: A) -> LocalBoxedGuard<'a, T, S, A>
+    where
+        F: 'a,
+        A: Allocator,
+    {
+        ScopeGuard::rebuild(guard, |v, dropfn, strategy| {
+            (v, Box::new_in(dropfn, alloc) as Box<_, A>, strategy)
+        })
+    }
+
+    /// Create a guard with the same armed state and debugging information,
+    /// from the value, closure and strategy `map(value, dropfn, strategy)`.
+    fn rebuild<U, G, S2>(
+        guard: Self,
+        map: impl FnOnce(T, F, S) -> (U, G, S2),
+    ) -> ScopeGuard<U, G, S2>
+    where
+        G: FnOnce(U),
+        S2: Strategy,
+    {
+        #[cfg(feature = "debug_info")]
+        let info = guard.info;
+        let armed = guard.armed;
+        let (value, dropfn, strategy) = ScopeGuard::into_raw_parts(guard);
+        let (value, dropfn, strategy) = map(value, dropfn, strategy);
+        let mut rebuilt = ScopeGuard::new(value, dropfn, strategy);
+        rebuilt.armed = armed;
+        #[cfg(feature = "debug_info")]
+        {
+            rebuilt.info = info;
+        }
+        rebuilt
+    }
+}
+
+// The box type of the boxed guard aliases, which also take the allocator `A`
+// with crate feature `allocator_api`.
+#[cfg(all(feature = "alloc", not(feature = "allocator_api")))]
+macro_rules! boxed_closure {
+    ($t:ty) => { Box<$t> };
+}
+
+#[cfg(feature = "allocator_api")]
+macro_rules! boxed_closure {
+    ($t:ty) => { Box<$t, A> };
+}
+
+/// A `ScopeGuard` with a boxed closure, for storing guards whose closure
+/// types differ or can't be named.
+///
+/// With crate feature `allocator_api`, the closure is boxed in the allocator
+/// `A`.
+///
+/// Requires crate feature `alloc`.
+#[cfg(feature = "alloc")]
+pub type BoxedGuard<'a, T, S = Always, #[cfg(feature = "allocator_api")] A = Global> =
+    ScopeGuard<T, boxed_closure!(dyn FnOnce(T) + Send + 'a), S>;
+
+/// A `ScopeGuard` with a boxed closure that is not required to be `Send`.
+///
+/// With crate feature `allocator_api`, the closure is boxed in the allocator
+/// `A`.
+///
+/// Requires crate feature `alloc`.
+#[cfg(feature = "alloc")]
+pub type LocalBoxedGuard<'a, T, S = Always, #[cfg(feature = "allocator_api")] A = Global> =
+    ScopeGuard<T, boxed_closure!(dyn FnOnce(T) + 'a), S>;
+
+/// A `ScopeGuard` without a value or type parameters, to hold a cleanup in a
+/// struct field. Create it with [`guard_dyn`](fn.guard_dyn.html).
+///
+/// With crate feature `allocator_api`, the closure is boxed in the allocator
+/// `A`; create such a guard with [`guard_dyn_in`](fn.guard_dyn_in.html).
+///
+/// Requires crate feature `alloc`.
+#[cfg(feature = "alloc")]
+pub type DynGuard<#[cfg(feature = "allocator_api")] A = Global> =
+    ScopeGuard<(), boxed_closure!(dyn FnOnce(()) + Send), Always>;
+
+/// A `ScopeGuard` that borrows its value, as created by
+/// [`guard_ref`](fn.guard_ref.html).
+pub type RefGuard<'a, T, F, S = Always> = ScopeGuard<&'a mut T, F, S>;
+
+/// Start building a `ScopeGuard` that owns `v`.
+///
+/// The builder selects the strategy and optional debugging information,
+/// and [`Builder::build`](struct.Builder.html#method.build) creates the
+/// guard from the closure.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// fn main() {
+///     let mut log = scopeguard::builder(Vec::new())
+///         .named("log")
+///         .track_caller()
+///         .build(|log| assert_eq!(log, ["done"]));
+///     log.push("done");
+/// }
+/// ```
+#[inline]
+pub fn builder<T>(v: T) -> Builder<T> {
+    Builder {
+        value: v,
+        #[cfg(feature = "debug_info")]
+        info: GuardInfo::default(),
+        strategy: Always,
+    }
+}
+
+/// Builder for a `ScopeGuard`, created by [`builder`](fn.builder.html).
+///
+/// The name and caller location are debugging information: with crate
+/// feature `debug_info`, the guard stores them and shows them in its `Debug`
+/// output. Without it, they are ignored, and the guard does not grow.
+#[must_use = "the builder does nothing unless `build` is called"]
+pub struct Builder<T, S = Always>
+where
+    S: Strategy,
+{
+    value: T,
+    #[cfg(feature = "debug_info")]
+    info: GuardInfo,
+    strategy: S,
+}
+
+impl<T, S> Builder<T, S>
+where
+    S: Strategy,
+{
+    /// Use the strategy `S2`, created with `Default`.
+    #[inline]
+    pub fn strategy<S2: Strategy + Default>(self) -> Builder<T, S2> {
+        self.with_strategy(S2::default())
+    }
+
+    /// Use the strategy `strategy`.
+    #[inline]
+    pub fn with_strategy<S2: Strategy>(self, strategy: S2) -> Builder<T, S2> {
+        Builder {
+            value: self.value,
+            #[cfg(feature = "debug_info")]
+            info: self.info,
+            strategy,
+        }
+    }
+
+    /// Use the `Always` strategy (this is the default).
+    #[inline]
+    pub fn always(self) -> Builder<T, Always> {
+        self.with_strategy(Always)
+    }
+
+    /// Use the `OnUnwind` strategy.
+    #[inline]
+    pub fn on_unwind(self) -> Builder<T, OnUnwind> {
+        self.with_strategy(OnUnwind)
+    }
+
+    /// Use the `OnSuccess` strategy.
+    #[inline]
+    pub fn on_success(self) -> Builder<T, OnSuccess> {
+        self.with_strategy(OnSuccess)
+    }
+
+    /// Name the guard, for debugging.
+    #[inline]
+    #[allow(unused_mut, unused_variables)]
+    pub fn named(mut self, name: &'static str) -> Self {
+        #[cfg(feature = "debug_info")]
+        {
+            self.info.name = Some(name);
+        }
+        self
+    }
+
+    /// Record the location of this call, for debugging.
+    #[inline]
+    #[track_caller]
+    #[allow(unused_mut)]
+    pub fn track_caller(mut self) -> Self {
+        #[cfg(feature = "debug_info")]
+        {
+            self.info.location = Some(Location::caller());
+        }
+        self
+    }
+
+    /// Create the guard with deferred closure `dropfn`.
+    #[inline]
+    pub fn build<F>(self, dropfn: F) -> ScopeGuard<T, F, S>
+    where
+        F: FnOnce(T),
+    {
+        #[allow(unused_mut)]
+        let mut guard = ScopeGuard::new(self.value, dropfn, self.strategy);
+        #[cfg(feature = "debug_info")]
+        {
+            guard.info = self.info;
+        }
+        guard
+    }
+}
+
+impl<T, S> fmt::Debug for Builder<T, S>
+where
+    T: fmt::Debug,
+    S: Strategy,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct(stringify!(Builder));
+        d.field("value", &self.value);
+        #[cfg(feature = "debug_info")]
+        d.field("name", &self.info.name);
+        d.finish()
+    }
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`.
+#[inline]
+pub const fn guard<T, F>(v: T, dropfn: F) -> ScopeGuard<T, F, Always>
+where
+    F: FnOnce(T),
+{
+    ScopeGuard::new(v, dropfn, Always)
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`,
+/// using the strategy `S`.
+///
+/// The strategy is the first type parameter, so that generic code can pick
+/// it with a turbofish: `guard_with::<S, _, _>(v, dropfn)`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::{guard_with, Strategy, ScopeGuard};
+///
+/// fn protect<S: Strategy + Default>(v: Vec<u8>) -> ScopeGuard<Vec<u8>, fn(Vec<u8>), S> {
+///     guard_with::<S, _, _>(v, drop)
+/// }
+///
+/// fn main() {
+///     let v = protect::<scopeguard::Always>(vec![1]);
+///     assert_eq!(*v, [1]);
+/// }
+/// ```
+#[inline]
+pub fn guard_with<S, T, F>(v: T, dropfn: F) -> ScopeGuard<T, F, S>
+where
+    S: Strategy + Default,
+    F: FnOnce(T),
+{
+    ScopeGuard::with_strategy(v, dropfn)
+}
+
+/// Create a new `ScopeGuard` that calls `pet` when the scope is exited, using
+/// the strategy `S`.
+///
+/// This ties hardware watchdog handling to a scope: `pet` feeds the watchdog
+/// or signals that a critical section was left cleanly. With `Always`, which
+/// is available in `no_std` builds, it runs on every exit. With a strategy
+/// that reports unwinding, such as `OnSuccess` or a user-defined strategy
+/// backed by a panic hook, the watchdog is left to expire when the scope
+/// panics, so that the device resets.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::Always;
+/// # struct Watchdog;
+/// # impl Watchdog { fn feed(&self) {} }
+/// # static WATCHDOG: Watchdog = Watchdog;
+///
+/// fn control_loop_step() {
+///     let _pet = scopeguard::guard_watchdog::<Always, _>(|| WATCHDOG.feed());
+///     // read sensors, update outputs
+/// }
+/// # fn main() { control_loop_step() }
+/// ```
+#[inline]
+pub fn guard_watchdog<S, F>(pet: F) -> ScopeGuard<(), impl FnOnce(()), S>
+where
+    S: Strategy + Default,
+    F: FnOnce(),
+{
+    ScopeGuard::with_strategy((), move |()| pet())
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`,
+/// only if `cond` is `true`.
+///
+/// If `cond` is `false`, `v` is dropped right away, `dropfn` never runs,
+/// and `None` is returned. The result can be stored as is, for example in a
+/// struct field of type `Option<ScopeGuard<T, F>>`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::Cell;
+///
+/// fn main() {
+///     let removed = Cell::new(false);
+///     let keep_temp_files = true;
+///     let cleanup = scopeguard::maybe_guard(!keep_temp_files, (), |()| removed.set(true));
+///     assert!(cleanup.is_none());
+///     drop(cleanup);
+///     assert!(!removed.get());
+/// }
+/// ```
+#[inline]
+#[must_use = "if unused the guard, if any, runs its closure immediately; bind it to a named variable, like `let _guard = ...`, to keep it until the end of the scope"]
+pub fn maybe_guard<T, F>(cond: bool, v: T, dropfn: F) -> Option<ScopeGuard<T, F, Always>>
+where
+    F: FnOnce(T),
+{
+    if cond {
+        Some(guard(v, dropfn))
+    } else {
+        None
+    }
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`,
+/// which only runs if `cond` is `true`.
+///
+/// Unlike [`maybe_guard`](fn.maybe_guard.html), the guard always owns the
+/// value, and the condition can be changed later through the
+/// [`WhenFlag`](struct.WhenFlag.html) strategy.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::ScopeGuard;
+///
+/// fn main() {
+///     let verbose = false;
+///     let mut report = scopeguard::guard_if(verbose, Vec::new(), |lines| {
+///         for line in lines {
+///             println!("{}", line);
+///         }
+///     });
+///     report.push("step 1 done");
+///     // Always report errors.
+///     let failed = true;
+///     if failed {
+///         ScopeGuard::strategy_mut(&mut report).0 = true;
+///     }
+/// }
+/// ```
+#[inline]
+pub const fn guard_if<T, F>(cond: bool, v: T, dropfn: F) -> ScopeGuard<T, F, WhenFlag>
+where
+    F: FnOnce(T),
+{
+    ScopeGuard::new(v, dropfn, WhenFlag(cond))
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`,
+/// which runs according to a strategy selected at runtime.
+///
+/// See [`RuntimeStrategy`](enum.RuntimeStrategy.html).
+#[inline]
+pub const fn guard_dyn_strategy<T, F>(
+    v: T,
+    dropfn: F,
+    strategy: RuntimeStrategy,
+) -> ScopeGuard<T, F, RuntimeStrategy>
+where
+    F: FnOnce(T),
+{
+    ScopeGuard::new(v, dropfn, strategy)
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`,
+/// which only runs if `should_run` returns `true` when the guard is dropped.
+///
+/// This covers one-off conditions without a dedicated
+/// [`Strategy`](trait.Strategy.html) type.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::Cell;
+///
+/// fn main() {
+///     let committed = Cell::new(false);
+///     let rolled_back = Cell::new(false);
+///     {
+///         let _rollback = scopeguard::guard_with_fn(
+///             (),
+///             |()| rolled_back.set(true),
+///             || !committed.get(),
+///         );
+///         committed.set(true);
+///     }
+///     assert!(!rolled_back.get());
+/// }
+/// ```
+#[inline]
+pub const fn guard_with_fn<T, F, P>(v: T, dropfn: F, should_run: P) -> ScopeGuard<T, F, WhenFn<P>>
+where
+    F: FnOnce(T),
+    P: FnOnce() -> bool,
+{
+    ScopeGuard::new(v, dropfn, WhenFn::new(should_run))
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`,
+/// which only runs if `should_run` returns `true` for the value when the guard
+/// is dropped. Otherwise the value is dropped.
+///
+/// This lets the guarded value itself decide whether it needs cleanup, like a
+/// transaction that was committed.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::ScopeGuard;
+///
+/// struct Transaction {
+///     committed: bool,
+/// }
+///
+/// impl Transaction {
+///     fn rollback(self) {
+///         println!("rolled back");
+///     }
+/// }
+///
+/// fn main() {
+///     let mut tx = scopeguard::guard_when(
+///         Transaction { committed: false },
+///         Transaction::rollback,
+///         |tx| !tx.committed,
+///     );
+///     // ...
+///     tx.committed = true;
+/// }
+/// ```
+#[inline]
+pub fn guard_when<T, F, P>(v: T, dropfn: F, should_run: P) -> ScopeGuard<T, impl FnOnce(T), Always>
+where
+    F: FnOnce(T),
+    P: FnOnce(&T) -> bool,
+{
+    guard(v, move |v| {
+        if should_run(&v) {
+            dropfn(v)
+        }
+    })
+}
+
+/// Create a new `ScopeGuard` owning `a` and `b` and with deferred closure
+/// `dropfn`, which receives them as separate arguments.
+///
+/// The guard derefs to the tuple `(a, b)`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// fn main() {
+///     let mut bufs = scopeguard::guard2(Vec::new(), String::new(), |v, s| {
+///         assert_eq!(v, [1]);
+///         assert_eq!(s, "x");
+///     });
+///     bufs.0.push(1);
+///     bufs.1.push('x');
+/// }
+/// ```
+#[inline]
+#[allow(clippy::type_complexity)]
+pub fn guard2<A, B, F>(a: A, b: B, dropfn: F) -> ScopeGuard<(A, B), impl FnOnce((A, B)), Always>
+where
+    F: FnOnce(A, B),
+{
+    guard((a, b), move |(a, b)| dropfn(a, b))
+}
+
+/// Create a new `ScopeGuard` owning `a`, `b` and `c` and with deferred closure
+/// `dropfn`, which receives them as separate arguments.
+///
+/// The guard derefs to the tuple `(a, b, c)`.
+#[inline]
+#[allow(clippy::type_complexity)]
+pub fn guard3<A, B, C, F>(
+    a: A,
+    b: B,
+    c: C,
+    dropfn: F,
+) -> ScopeGuard<(A, B, C), impl FnOnce((A, B, C)), Always>
+where
+    F: FnOnce(A, B, C),
+{
+    guard((a, b, c), move |(a, b, c)| dropfn(a, b, c))
+}
+
+/// Create a new `ScopeGuard` owning `v` and the context `ctx`, with deferred
+/// closure `dropfn`, which receives both.
+///
+/// Unlike [`guard2`](fn.guard2.html), the guard derefs to `v` alone; the
+/// context is only handed to the closure.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::RefCell;
+///
+/// struct Logger(RefCell<Vec<String>>);
+///
+/// fn main() {
+///     let logger = Logger(RefCell::new(Vec::new()));
+///     {
+///         let mut buf = scopeguard::guard_with_context(Vec::new(), &logger, |buf, log| {
+///             log.0.borrow_mut().push(format!("released {} bytes", buf.len()));
+///         });
+///         buf.extend_from_slice(b"data");
+///     }
+///     assert_eq!(*logger.0.borrow(), ["released 4 bytes"]);
+/// }
+/// ```
+#[inline]
+pub fn guard_with_context<T, C, F>(v: T, ctx: C, dropfn: F) -> ScopeGuard<T, impl FnOnce(T), Always>
+where
+    F: FnOnce(T, C),
+{
+    guard(v, move |v| dropfn(v, ctx))
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`,
+/// which receives a mutable reference to the value. The value is dropped
+/// after the closure returns, or when it panics.
+///
+/// Use it when the cleanup only needs to adjust the value, and the value's
+/// own `Drop` should still run afterwards.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::io::{BufWriter, Write};
+///
+/// fn main() {
+///     let mut out = scopeguard::guard_mut(BufWriter::new(Vec::new()), |out| {
+///         let _ = out.write_all(b"\n");
+///     });
+///     out.write_all(b"done").unwrap();
+/// }
+/// ```
+#[inline]
+pub fn guard_mut<T, F>(v: T, dropfn: F) -> ScopeGuard<T, impl FnOnce(T), Always>
+where
+    F: FnOnce(&mut T),
+{
+    guard(v, move |mut v| dropfn(&mut v))
+}
+
+/// Create a new `ScopeGuard` that borrows `v` mutably and calls `dropfn` with
+/// the borrow on scope exit, for example to restore an invariant of a value
+/// owned by the caller.
+///
+/// Methods of `T` can be called on the guard directly; to replace the whole
+/// value, write through the reference: `**guard = value`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// fn main() {
+///     let mut stack = vec![1, 2];
+///     {
+///         let depth = stack.len();
+///         let mut stack = scopeguard::guard_ref(&mut stack, |stack| stack.truncate(depth));
+///         stack.push(3);
+///         stack.push(4);
+///         assert_eq!(stack.len(), 4);
+///     }
+///     assert_eq!(stack, [1, 2]);
+/// }
+/// ```
+#[inline]
+pub fn guard_ref<'a, T, F>(v: &'a mut T, dropfn: F) -> RefGuard<'a, T, F>
+where
+    T: ?Sized,
+    F: FnOnce(&'a mut T),
+{
+    guard(v, dropfn)
+}
+
+/// Create a new `ScopeGuard` owning `v` and with the fallible deferred closure
+/// `dropfn`. If it returns an error, the error is passed to `on_error`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::fs::File;
+/// use std::io::Write;
+///
+/// fn main() {
+///     # let _ = std::fs::create_dir("target");
+///     let file = File::create("target/guard_try.txt").unwrap();
+///     let mut file = scopeguard::guard_try(file, |f| f.sync_all(), |err| {
+///         eprintln!("sync failed: {}", err);
+///     });
+///     file.write_all(b"data").unwrap();
+///     # drop(file);
+///     # let _ = std::fs::remove_file("target/guard_try.txt");
+/// }
+/// ```
+#[inline]
+pub fn guard_try<T, E, F, H>(v: T, dropfn: F, on_error: H) -> ScopeGuard<T, impl FnOnce(T), Always>
+where
+    F: FnOnce(T) -> Result<(), E>,
+    H: FnOnce(E),
+{
+    guard(v, move |v| {
+        if let Err(err) = dropfn(v) {
+            on_error(err);
+        }
+    })
+}
+
+/// Create a new [`BoxedGuard`](type.BoxedGuard.html) owning `v` and with
+/// deferred closure `dropfn`, which is boxed.
+///
+/// Guards with different closures have the same type, so they can be stored
+/// together.
+///
+/// Requires crate feature `alloc`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::{guard_boxed, BoxedGuard};
+///
+/// fn main() {
+///     let prefix = String::from("closing");
+///     let files: Vec<BoxedGuard<&str>> = vec![
+///         guard_boxed("a.txt", |name| println!("closing {}", name)),
+///         guard_boxed("b.txt", move |name| println!("{} {}", prefix, name)),
+///     ];
+///     assert_eq!(*files[1], "b.txt");
+/// }
+/// ```
+#[cfg(feature = "alloc")]
+#[inline]
+pub fn guard_boxed<'a, T, F>(v: T, dropfn: F) -> BoxedGuard<'a, T>
+where
+    F: FnOnce(T) + Send + 'a,
+{
+    ScopeGuard::new(v, Box::new(dropfn), Always)
+}
+
+/// Create a new [`DynGuard`](type.DynGuard.html) that calls `dropfn` on scope
+/// exit, including during unwinding.
+///
+/// Requires crate feature `alloc`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::{guard_dyn, DynGuard};
+///
+/// struct Server {
+///     port: u16,
+///     cleanup: Option<DynGuard>,
+/// }
+///
+/// fn main() {
+///     let mut server = Server { port: 8080, cleanup: None };
+///     let port = server.port;
+///     server.cleanup = Some(guard_dyn(move || println!("released port {}", port)));
+/// }
+/// ```
+#[cfg(feature = "alloc")]
+#[inline]
+pub fn guard_dyn<F>(dropfn: F) -> DynGuard
+where
+    F: FnOnce() + Send + 'static,
+{
+    guard_boxed((), move |()| dropfn())
+}
+
+/// Create a new [`BoxedGuard`](type.BoxedGuard.html) owning `v` and with
+/// deferred closure `dropfn`, which is boxed in the allocator `alloc`.
+///
+/// Requires crate feature `allocator_api`.
+///
+/// ```
+/// #![feature(allocator_api)]
+/// extern crate scopeguard;
+///
+/// use std::alloc::Global;
+///
+/// fn main() {
+///     let _file = scopeguard::guard_boxed_in("a.txt", |name| println!("closing {}", name), Global);
+/// }
+/// ```
+#[cfg(feature = "allocator_api")]
+#[inline]
+pub fn guard_boxed_in<'a, T, F, A>(v: T, dropfn: F, alloc: A) -> BoxedGuard<'a, T, Always, A>
+where
+    F: FnOnce(T) + Send + 'a,
+    A: Allocator,
+{
+    ScopeGuard::new(v, Box::new_in(dropfn, alloc), Always)
+}
+
+/// Create a new [`DynGuard`](type.DynGuard.html) that calls `dropfn` on scope
+/// exit, including during unwinding. The closure is boxed in the allocator
+/// `alloc`.
+///
+/// Requires crate feature `allocator_api`.
+#[cfg(feature = "allocator_api")]
+#[inline]
+pub fn guard_dyn_in<F, A>(dropfn: F, alloc: A) -> DynGuard<A>
+where
+    F: FnOnce() + Send + 'static,
+    A: Allocator,
+{
+    guard_boxed_in((), move |()| dropfn(), alloc)
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`.
+///
+/// The closure runs on regular scope exit, when not unwinding; see
+/// [`OnSuccess`](struct.OnSuccess.html).
+#[inline]
+pub const fn guard_on_success<T, F>(v: T, dropfn: F) -> ScopeGuard<T, F, OnSuccess>
+where
+    F: FnOnce(T),
+{
+    ScopeGuard::new(v, dropfn, OnSuccess)
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`.
+///
+/// The closure runs on scope exit through unwinding; see
+/// [`OnUnwind`](struct.OnUnwind.html).
+///
+/// ## Examples
+///
+/// For performance reasons, or to emulate “only run guard on unwind” in
+/// no-std environments, we can also use the default guard and simply manually
+/// defuse it at the end of scope like the following example. (The performance
+/// reason would be if the [`OnUnwind`]'s call to [std::thread::panicking()] is
+/// an issue.)
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::ScopeGuard;
+/// # fn main() {
+/// {
+///     let guard = scopeguard::guard((), |_| {});
+///
+///     // rest of the code here
+///
+///     // we reached the end of scope without unwinding - defuse it
+///     ScopeGuard::into_inner(guard);
+/// }
+/// # }
+/// ```
+#[inline]
+pub fn guard_on_unwind<T, F>(v: T, dropfn: F) -> ScopeGuard<T, F, OnUnwind>
+where
+    F: FnOnce(T),
+{
+    ScopeGuard::new(v, dropfn, OnUnwind)
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`,
+/// which only runs if the guard is dropped within `window` from its creation.
+///
+/// This is for compensating actions that are only meaningful while a lease
+/// or reservation is still valid. When the window has passed, the value is
+/// dropped without calling the closure.
+///
+/// Requires crate feature `use_std`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let _release = scopeguard::guard_within(Duration::from_secs(30), "lease-1", |lease| {
+///         println!("releasing {} before it expires", lease);
+///     });
+/// }
+/// ```
+#[cfg(feature = "use_std")]
+pub fn guard_within<T, F>(
+    window: std::time::Duration,
+    v: T,
+    dropfn: F,
+) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    F: FnOnce(T),
+{
+    let created = std::time::Instant::now();
+    guard(v, move |v| {
+        if created.elapsed() <= window {
+            dropfn(v)
+        }
+    })
+}
+
+/// Create a new `ScopeGuard` that measures how long its closure takes.
+///
+/// After `dropfn` has run, `record` is called with the time it took, so that
+/// slow cleanups (flushing, closing connections) can be fed into a histogram
+/// or other metrics. The duration is also recorded if `dropfn` panics.
+///
+/// Requires crate feature `use_std`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let mut samples: Vec<Duration> = Vec::new();
+///     {
+///         let _flush = scopeguard::guard_timed(
+///             (),
+///             |()| { /* flush buffers */ },
+///             |took| samples.push(took),
+///         );
+///     }
+///     assert_eq!(samples.len(), 1);
+/// }
+/// ```
+#[cfg(feature = "use_std")]
+pub fn guard_timed<T, F, R>(v: T, dropfn: F, record: R) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    F: FnOnce(T),
+    R: FnOnce(std::time::Duration),
+{
+    guard(v, move |v| {
+        let started = std::time::Instant::now();
+        let _record = guard((), move |()| record(started.elapsed()));
+        dropfn(v)
+    })
+}
+
+/// Create a new `ScopeGuard` that records how long its closure takes in the
+/// histogram `name` of the [`metrics`](https://docs.rs/metrics) crate.
+///
+/// This is [`guard_timed`](fn.guard_timed.html) with a recorder that feeds the
+/// duration, in seconds, to `metrics::histogram!(name)`, so that slow
+/// cleanups show up in the dashboards of the installed metrics recorder.
+///
+/// Requires crate features `use_std` and `metrics`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// fn main() {
+///     let _flush = scopeguard::guard_timed_histogram(
+///         "cache.flush.seconds",
+///         (),
+///         |()| { /* flush buffers */ },
+///     );
+/// }
+/// ```
+#[cfg(all(feature = "use_std", feature = "metrics"))]
+pub fn guard_timed_histogram<T, F>(
+    name: &'static str,
+    v: T,
+    dropfn: F,
+) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    F: FnOnce(T),
+{
+    guard_timed(v, dropfn, move |took| {
+        ::metrics::histogram!(name).record(took)
+    })
+}
+
+/// Invoke `f`, catching a panic if one occurs, like `std::panic::catch_unwind`
+/// but without requiring `f` to be `UnwindSafe`.
+///
+/// Guards typically hold `&mut` references or `Cell`s to the state they
+/// restore, which makes closures that capture them not `UnwindSafe`, so they
+/// can't be passed to `catch_unwind` without wrapping them in
+/// `AssertUnwindSafe`. This function does that wrapping.
+///
+/// This is sound: `UnwindSafe` is not a memory safety property, only a
+/// reminder that state may be observed in a broken state after a panic. The
+/// guards created inside `f` run during unwinding, before this function
+/// returns, so the invariants they protect have already been restored when
+/// the caller sees the `Err`. State that is modified by `f` but not protected
+/// by a guard may still be left inconsistent.
+///
+/// Requires crate feature `use_std`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// fn main() {
+///     let mut stack = vec![1, 2, 3];
+///     let result = scopeguard::catch_unwind_guarded(|| {
+///         let len = stack.len();
+///         let mut stack = scopeguard::guard(&mut stack, move |s| s.truncate(len));
+///         stack.push(4);
+///         panic!("interrupted");
+///     });
+///     assert!(result.is_err());
+///     assert_eq!(stack, [1, 2, 3]);
+/// }
+/// ```
+#[cfg(feature = "use_std")]
+pub fn catch_unwind_guarded<F, R>(f: F) -> std::thread::Result<R>
+where
+    F: FnOnce() -> R,
+{
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f))
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`.
+/// If the closure panics while the thread is already unwinding, the panic is
+/// caught and its payload passed to `on_panic`, instead of aborting the
+/// process.
+///
+/// A panic of the closure on regular scope exit propagates as usual. The
+/// panic hook still reports the caught panic.
+///
+/// Requires crate feature `use_std`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::panic;
+///
+/// fn main() {
+///     let result = panic::catch_unwind(|| {
+///         let _conn = scopeguard::guard_catch_unwind(
+///             (),
+///             |()| panic!("connection reset while closing"),
+///             |payload| eprintln!("cleanup panicked: {:?}", payload.downcast_ref::<&str>()),
+///         );
+///         panic!("request failed");
+///     });
+///     assert!(result.is_err());
+/// }
+/// ```
+#[cfg(feature = "use_std")]
+pub fn guard_catch_unwind<T, F, P>(v: T, dropfn: F, on_panic: P) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    F: FnOnce(T),
+    P: FnOnce(Box<dyn std::any::Any + Send>),
+{
+    guard(v, move |v| {
+        if std::thread::panicking() {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| dropfn(v)));
+            if let Err(payload) = result {
+                on_panic(payload);
+            }
+        } else {
+            dropfn(v);
+        }
+    })
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`.
+/// If the closure panics, the process aborts immediately, without unwinding
+/// through partially restored state.
+///
+/// With crate feature `use_std`, a message is printed to stderr and the
+/// process is aborted with `std::process::abort`. Without it, the panic is
+/// turned into a panic during unwinding, which aborts.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// fn main() {
+///     let mut len = 0;
+///     {
+///         // Restoring `len` must not fail halfway.
+///         let _restore = scopeguard::guard_abort_on_panic(&mut len, |len| *len = 0);
+///     }
+///     assert_eq!(len, 0);
+/// }
+/// ```
+pub fn guard_abort_on_panic<T, F>(v: T, dropfn: F) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    F: FnOnce(T),
+{
+    guard(v, move |v| {
+        let bomb = AbortOnDrop;
+        dropfn(v);
+        std::mem::forget(bomb);
+    })
+}
+
+// Armed around a cleanup closure; it is only dropped if the closure panics.
+struct AbortOnDrop;
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        #[cfg(feature = "use_std")]
+        {
+            eprintln!("scopeguard: cleanup closure panicked, aborting");
+            std::process::abort();
+        }
+        #[cfg(not(feature = "use_std"))]
+        panic!("scopeguard: cleanup closure panicked, aborting");
+    }
+}
+
+/// The identity of a cleanup that must not run reentrantly.
+///
+/// Declare it as a static and create guards with
+/// [`guard_non_reentrant`](fn.guard_non_reentrant.html).
+///
+/// Requires crate feature `use_std`.
+#[cfg(feature = "use_std")]
+#[derive(Debug)]
+pub struct NonReentrant {
+    name: &'static str,
+    on_reentry: Option<fn(&'static NonReentrant)>,
+}
+
+#[cfg(feature = "use_std")]
+impl NonReentrant {
+    /// Create a new cleanup identity with a name for diagnostics.
+    pub const fn new(name: &'static str) -> Self {
+        NonReentrant {
+            name,
+            on_reentry: None,
+        }
+    }
+
+    /// Call `f` with this identity each time a guard skips its closure
+    /// because the cleanup is already running on the thread.
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::NonReentrant;
+    ///
+    /// static FLUSH: NonReentrant = NonReentrant::new("flush")
+    ///     .on_reentry(|id| eprintln!("skipped reentrant cleanup {}", id.name()));
+    /// # fn main() {}
+    /// ```
+    pub const fn on_reentry(self, f: fn(&'static NonReentrant)) -> Self {
+        NonReentrant {
+            name: self.name,
+            on_reentry: Some(f),
+        }
+    }
+
+    /// Return the name of the cleanup.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Return `true` if a cleanup with this identity is running on the
+    /// current thread.
+    pub fn is_running(&'static self) -> bool {
+        let id = self as *const Self as usize;
+        RUNNING_CLEANUPS
+            .try_with(|running| running.borrow().contains(&id))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(feature = "use_std")]
+thread_local! {
+    static RUNNING_CLEANUPS: std::cell::RefCell<Vec<usize>> =
+        const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Create a new `ScopeGuard` whose closure is skipped if a cleanup with the
+/// same identity `id` is already running on the current thread.
+///
+/// This breaks cycles where a cleanup transitively triggers itself, for
+/// example a guard that flushes a logger and logs a failure while doing so.
+/// Instead of recursing, the nested guard skips its closure and drops its
+/// value. The skip is reported to the callback set with
+/// [`NonReentrant::on_reentry`](struct.NonReentrant.html#method.on_reentry),
+/// and with crate feature `log`, as a `log::debug!` record. Code that needs to
+/// know whether its cleanup will be skipped can check
+/// [`NonReentrant::is_running`](struct.NonReentrant.html#method.is_running).
+///
+/// While the thread's thread-local storage is being destroyed, reentrancy can
+/// no longer be tracked, and the closure always runs.
+///
+/// Requires crate feature `use_std`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::Cell;
+/// use scopeguard::NonReentrant;
+///
+/// static FLUSH: NonReentrant = NonReentrant::new("flush");
+///
+/// fn flush(count: &Cell<u32>) {
+///     let _g = scopeguard::guard_non_reentrant(&FLUSH, (), |()| {
+///         count.set(count.get() + 1);
+///         // Flushing ends up flushing again.
+///         flush(count);
+///     });
+/// }
+///
+/// fn main() {
+///     let count = Cell::new(0);
+///     flush(&count);
+///     assert_eq!(count.get(), 1);
+/// }
+/// ```
+#[cfg(feature = "use_std")]
+pub fn guard_non_reentrant<T, F>(
+    id: &'static NonReentrant,
+    v: T,
+    dropfn: F,
+) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    F: FnOnce(T),
+{
+    guard(v, move |v| {
+        if id.is_running() {
+            #[cfg(feature = "log")]
+            ::log::debug!("skipped reentrant cleanup {:?}", id.name);
+            if let Some(on_reentry) = id.on_reentry {
+                on_reentry(id);
+            }
+            return;
+        }
+        let addr = id as *const NonReentrant as usize;
+        let tracked = RUNNING_CLEANUPS
+            .try_with(|running| running.borrow_mut().push(addr))
+            .is_ok();
+        let _running = guard((), |()| {
+            if tracked {
+                let _ = RUNNING_CLEANUPS.try_with(|running| running.borrow_mut().pop());
+            }
+        });
+        dropfn(v)
+    })
+}
+
+/// Run `body` up to `attempts` times until it succeeds, running `between`
+/// after each failed attempt.
+///
+/// An attempt fails if `body` returns `Err` or panics. After each failed
+/// attempt `between` runs to reset any state the attempt left behind; this
+/// includes the final attempt, so the cleanup has run when this function
+/// gives up. It then returns the last error, or resumes the last panic.
+///
+/// `attempts` is treated as at least one.
+///
+/// Panics from `body` are caught with `catch_unwind`, so state that `body`
+/// captures may be observed in a broken state by the next attempt; `between`
+/// is responsible for restoring it.
+///
+/// Requires crate feature `use_std`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// fn main() {
+///     let mut tries = 0;
+///     let mut resets = 0;
+///     let result = scopeguard::retry_with_cleanup(
+///         3,
+///         || {
+///             tries += 1;
+///             if tries < 3 { Err("busy") } else { Ok(tries) }
+///         },
+///         || resets += 1,
+///     );
+///     assert_eq!(result, Ok(3));
+///     assert_eq!(resets, 2);
+/// }
+/// ```
+#[cfg(feature = "use_std")]
+pub fn retry_with_cleanup<T, E, B, C>(attempts: usize, mut body: B, mut between: C) -> Result<T, E>
+where
+    B: FnMut() -> Result<T, E>,
+    C: FnMut(),
+{
+    use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+    let mut attempt = 1;
+    loop {
+        let last = attempt >= attempts;
+        match catch_unwind(AssertUnwindSafe(&mut body)) {
+            Ok(Ok(value)) => return Ok(value),
+            Ok(Err(error)) => {
+                between();
+                if last {
+                    return Err(error);
+                }
+            }
+            Err(payload) => {
+                between();
+                if last {
+                    resume_unwind(payload);
+                }
+            }
+        }
+        attempt += 1;
+    }
+}
+
+/// A named class of cleanups that can be switched off at runtime.
+///
+/// Declare categories as statics, create guards in them with
+/// [`guard_in`](fn.guard_in.html), and use [`set_enabled`](fn.set_enabled.html)
+/// to disable or re-enable every guard of the category. The flag is read when
+/// a guard is dropped, so it also applies to guards that already exist.
+///
+/// Categories are enabled when created.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::Category;
+///
+/// static DELETE_TEMP_DATA: Category = Category::new("delete temp data");
+///
+/// fn main() {
+///     // While investigating an incident, keep the temporary data around.
+///     scopeguard::set_enabled(&DELETE_TEMP_DATA, false);
+///
+///     let mut deleted = false;
+///     drop(scopeguard::guard_in(&DELETE_TEMP_DATA, (), |()| deleted = true));
+///     assert!(!deleted);
+/// }
+/// ```
+pub struct Category {
+    name: &'static str,
+    enabled: AtomicBool,
+}
+
+impl Category {
+    /// Create a new, enabled category.
+    pub const fn new(name: &'static str) -> Self {
+        Category {
+            name,
+            enabled: AtomicBool::new(true),
+        }
+    }
+
+    /// Return the name of the category.
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    /// Return `true` if guards of this category currently run their closure.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+impl fmt::Debug for Category {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct(stringify!(Category))
+            .field("name", &self.name)
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+/// Enable or disable all guards of `category`.
+///
+/// Guards created with [`guard_in`](fn.guard_in.html) check the category when
+/// they are dropped; while it is disabled, their closure does not run and the
+/// value is dropped normally.
+pub fn set_enabled(category: &Category, enabled: bool) {
+    category.enabled.store(enabled, Ordering::Relaxed);
+}
+
+/// Create a new `ScopeGuard` in `category`.
+///
+/// The closure runs on scope exit, like with [`guard`](fn.guard.html), unless
+/// the category is disabled at that point.
+pub fn guard_in<T, F>(category: &'static Category, v: T, dropfn: F) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    F: FnOnce(T),
+{
+    guard(v, move |v| {
+        if category.is_enabled() {
+            dropfn(v)
+        }
+    })
+}
+
+// ScopeGuard can be Sync even if F isn't because the closure is
+// not accessible from shared references, unless it is Sync itself
+// (see `closure_ref` and the `Clone` impl).
+unsafe impl<T, F, S> Sync for ScopeGuard<T, F, S>
+where
+    T: Sync,
+    F: FnOnce(T),
+    S: Strategy + Sync,
+{
+}
+
+/// Create a `ScopeGuard` from a `(value, dropfn)` pair.
+///
+/// This is the same as `ScopeGuard::with_strategy(value, dropfn)`, and lets
+/// generic code produce guards through `Into`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::ScopeGuard;
+///
+/// fn main() {
+///     let guards: Vec<ScopeGuard<i32, fn(i32)>> = vec![(1, drop as fn(i32)), (2, drop)]
+///         .into_iter()
+///         .map(Into::into)
+///         .collect();
+///     assert_eq!(*guards[1], 2);
+/// }
+/// ```
+impl<T, F, S> From<(T, F)> for ScopeGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy + Default,
+{
+    #[inline]
+    fn from((v, dropfn): (T, F)) -> Self {
+        ScopeGuard::with_strategy(v, dropfn)
+    }
+}
+
+/// Clone the value and the closure. Each clone is a separate guard that runs
+/// its own copy of the closure.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use std::thread;
+/// use scopeguard::guard;
+///
+/// static DONE: AtomicUsize = AtomicUsize::new(0);
+///
+/// fn main() {
+///     let worker = guard((), |()| {
+///         DONE.fetch_add(1, Ordering::SeqCst);
+///     });
+///     let handles: Vec<_> = (0..4)
+///         .map(|_| {
+///             let worker = worker.clone();
+///             thread::spawn(move || drop(worker))
+///         })
+///         .collect();
+///     drop(worker);
+///     for handle in handles {
+///         handle.join().unwrap();
+///     }
+///     assert_eq!(DONE.load(Ordering::SeqCst), 5);
+/// }
+/// ```
+///
+/// The closure must be `Sync`, because the guard can be shared between
+/// threads even if its closure is not, and cloning reads the closure through
+/// a shared reference. A closure that captures an `Rc` can't be cloned:
+///
+/// ```compile_fail
+/// extern crate scopeguard;
+///
+/// use std::rc::Rc;
+/// use scopeguard::guard;
+///
+/// fn main() {
+///     let shared = Rc::new(());
+///     let g = guard((), move |()| drop(shared));
+///     let _g2 = g.clone();
+/// }
+/// ```
+impl<T, F, S> Clone for ScopeGuard<T, F, S>
+where
+    T: Clone,
+    F: FnOnce(T) + Clone + Sync,
+    S: Strategy + Clone,
+{
+    fn clone(&self) -> Self {
+        ScopeGuard {
+            value: self.value.clone(),
+            dropfn: self.dropfn.clone(),
+            armed: self.armed,
+            strategy: self.strategy.clone(),
+            #[cfg(feature = "debug_info")]
+            info: self.info,
+        }
+    }
+}
+
+/// Compare the protected values. The closures and strategies may differ.
+///
+/// To compare a guard with a plain value, dereference it: `*guard == value`.
+impl<T, F, S, G, S2> PartialEq<ScopeGuard<T, G, S2>> for ScopeGuard<T, F, S>
+where
+    T: PartialEq,
+    F: FnOnce(T),
+    S: Strategy,
+    G: FnOnce(T),
+    S2: Strategy,
+{
+    #[inline]
+    fn eq(&self, other: &ScopeGuard<T, G, S2>) -> bool {
+        *self.value == *other.value
+    }
+}
+
+impl<T, F, S> Eq for ScopeGuard<T, F, S>
+where
+    T: Eq,
+    F: FnOnce(T),
+    S: Strategy,
+{
+}
+
+/// Compare the protected values. The closures and strategies may differ.
+impl<T, F, S, G, S2> PartialOrd<ScopeGuard<T, G, S2>> for ScopeGuard<T, F, S>
+where
+    T: PartialOrd,
+    F: FnOnce(T),
+    S: Strategy,
+    G: FnOnce(T),
+    S2: Strategy,
+{
+    #[inline]
+    fn partial_cmp(&self, other: &ScopeGuard<T, G, S2>) -> Option<cmp::Ordering> {
+        (*self.value).partial_cmp(&*other.value)
+    }
+}
+
+impl<T, F, S> Ord for ScopeGuard<T, F, S>
+where
+    T: Ord,
+    F: FnOnce(T),
+    S: Strategy,
+{
+    #[inline]
+    fn cmp(&self, other: &Self) -> cmp::Ordering {
+        (*self.value).cmp(&*other.value)
+    }
+}
+
+/// Hash the protected value, consistently with `PartialEq`.
+impl<T, F, S> Hash for ScopeGuard<T, F, S>
+where
+    T: Hash,
+    F: FnOnce(T),
+    S: Strategy,
+{
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (*self.value).hash(state)
+    }
+}
+
+impl<T, F, S> Deref for ScopeGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T, F, S> DerefMut for ScopeGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T, F, S> Drop for ScopeGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn drop(&mut self) {
+        // The strategy may run user code, so ask it before taking any field,
+        // and drop the value and the closure if it panics.
+        let on_panic = DropFields(&mut self.value, &mut self.dropfn);
+        let should_run = self.armed && self.strategy.should_run();
+        std::mem::forget(on_panic);
+        // This is OK because the fields are `ManuallyDrop`s
+        // which will not be dropped by the compiler.
+        // Each field is taken or dropped exactly once, here.
+        let dropfn = unsafe { ManuallyDrop::take(&mut self.dropfn) };
+        if should_run {
+            dropfn(unsafe { ManuallyDrop::take(&mut self.value) });
+        } else {
+            // Drop the value in place, it may be pinned.
+            drop(dropfn);
+            unsafe { ManuallyDrop::drop(&mut self.value) };
+        }
+    }
+}
+
+/// Drops a guard's value and closure in place, if the guard's strategy panics.
+struct DropFields<'a, T, F>(&'a mut ManuallyDrop<T>, &'a mut ManuallyDrop<F>);
+
+impl<'a, T, F> Drop for DropFields<'a, T, F> {
+    fn drop(&mut self) {
+        // The guard is being dropped and does not touch the fields again.
+        unsafe {
+            ManuallyDrop::drop(self.1);
+            ManuallyDrop::drop(self.0);
+        }
+    }
+}
+
+impl<T, F, S> fmt::Debug for ScopeGuard<T, F, S>
+where
+    T: fmt::Debug,
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut d = f.debug_struct(stringify!(ScopeGuard));
+        d.field("value", &*self.value);
+        d.field("strategy", &format_args!("{}", type_name::<S>()));
+        d.field("armed", &self.armed);
+        #[cfg(feature = "debug_info")]
+        {
+            if let Some(name) = self.info.name {
+                d.field("name", &name);
+            }
+            if let Some(location) = self.info.location {
+                d.field("location", &format_args!("{}", location));
+            }
+        }
+        d.finish()
+    }
+}
+
+/// Format the protected value.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::guard;
+///
+/// fn main() {
+///     let path = guard("data.tmp", |_| {});
+///     assert_eq!(format!("writing {}", path), "writing data.tmp");
+/// }
+/// ```
+impl<T, F, S> fmt::Display for ScopeGuard<T, F, S>
+where
+    T: fmt::Display,
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&*self.value, f)
+    }
+}
+
+/// Serialize the protected value.
+///
+/// Requires crate feature `serde`.
+#[cfg(feature = "serde")]
+impl<T, F, S> serde::Serialize for ScopeGuard<T, F, S>
+where
+    T: serde::Serialize,
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn serialize<Ser>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error>
+    where
+        Ser: serde::Serializer,
+    {
+        (*self.value).serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::{Cell, RefCell};
+    use std::panic::catch_unwind;
+    use std::panic::AssertUnwindSafe;
+
+    #[test]
+    fn test_defer() {
+        let drops = Cell::new(0);
+        defer!(drops.set(1000));
+        assert_eq!(drops.get(), 0);
+    }
+
+    #[test]
+    fn test_defer_named() {
+        let drops = Cell::new(0);
+        {
+            defer!(first: {
+                drops.set(drops.get() + 1);
+            });
+            defer!(second: {
+                drops.set(drops.get() + 10);
+            });
+            assert!(ScopeGuard::is_armed(&first));
+            ScopeGuard::cancel(second);
+        }
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn test_defer_move() {
+        let log = RefCell::new(Vec::new());
+        {
+            let name = String::from("tmp");
+            let mut count = 1;
+            defer!(move(name, count) {
+                log.borrow_mut().push(format!("{} {}", name, count));
+            });
+            count += 1;
+            assert_eq!(count, 2);
+        }
+        assert_eq!(*log.borrow(), ["tmp 1"]);
+    }
+
+    #[test]
+    fn test_defer_value() {
+        let log = RefCell::new(Vec::new());
+        {
+            let buffer = vec![1];
+            defer!(mut buffer => |b: Vec<i32>| log.borrow_mut().extend(b));
+            buffer.push(2);
+            let label = "done";
+            defer!(label => |l| log.borrow_mut().push(l.len() as i32));
+            assert_eq!(*label, "done");
+        }
+        assert_eq!(*log.borrow(), [4, 1, 2]);
+    }
+
+    #[test]
+    fn test_finally() {
+        fn parse(s: &str, log: &RefCell<Vec<&'static str>>) -> Result<u8, std::num::ParseIntError> {
+            let n = finally!({
+                s.parse::<u8>()?
+            } cleanup {
+                log.borrow_mut().push("cleanup");
+            });
+            log.borrow_mut().push("parsed");
+            Ok(n)
+        }
+        let log = RefCell::new(Vec::new());
+        assert_eq!(parse("7", &log), Ok(7));
+        assert!(parse("x", &log).is_err());
+        assert_eq!(*log.borrow(), ["cleanup", "parsed", "cleanup"]);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_try_defer() {
+        let steps = Cell::new(0);
+        {
+            try_defer!(ignore, {
+                steps.set(steps.get() + 1);
+                let _: u8 = "300".parse()?;
+                steps.set(steps.get() + 10);
+            });
+        }
+        assert_eq!(steps.get(), 1);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            try_defer!(panic, {
+                let _: u8 = "x".parse()?;
+            });
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_cancel_defer() {
+        let drops = Cell::new(0);
+        {
+            defer!(kept: {
+                drops.set(drops.get() + 1);
+            });
+            defer!(cancelled: {
+                drops.set(drops.get() + 10);
+            });
+            cancel_defer!(cancelled);
+            assert!(ScopeGuard::is_armed(&kept));
+        }
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn test_guard_macro() {
+        let runs = Cell::new(0);
+        drop(guard!(1, |n| runs.set(runs.get() + n)));
+        drop(guard!(always, 10, |n| runs.set(runs.get() + n)));
+        drop(guard!(on_success, 100, |n| runs.set(runs.get() + n)));
+        drop(guard!(on_unwind, 1000, |n| runs.set(runs.get() + n)));
+        drop(guard!(never, 10000, |n| runs.set(runs.get() + n),));
+        assert_eq!(runs.get(), 111);
+    }
+
+    #[test]
+    fn test_scope() {
+        let log = RefCell::new(Vec::new());
+        let value = scope! {
+            defer { log.borrow_mut().push(1); };
+            let x = 2;
+            defer { log.borrow_mut().push(x) }
+            log.borrow_mut().push(0);
+            x * 10
+        };
+        assert_eq!(value, 20);
+        assert_eq!(*log.borrow(), [0, 2, 1]);
+        scope! {
+            defer { log.borrow_mut().clear(); }
+        }
+        assert!(log.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_scope_long_block() {
+        let log = RefCell::new(Vec::new());
+        let total = scope! {
+            let mut total = 0;
+            total += 1;
+            total += 2;
+            total += 3;
+            total += 4;
+            total += 5;
+            total += 6;
+            total += 7;
+            total += 8;
+            total += 9;
+            total += 10;
+            defer { log.borrow_mut().push(10); }
+            total += 11;
+            total += 12;
+            total += 13;
+            total += 14;
+            total += 15;
+            total += 16;
+            total += 17;
+            total += 18;
+            total += 19;
+            total += 20;
+            defer { log.borrow_mut().push(20); }
+            total += 21;
+            total += 22;
+            total += 23;
+            total += 24;
+            total += 25;
+            total += 26;
+            total += 27;
+            total += 28;
+            total += 29;
+            total += 30;
+            defer { log.borrow_mut().push(30); }
+            total += 31;
+            total += 32;
+            total += 33;
+            total += 34;
+            total += 35;
+            total += 36;
+            total += 37;
+            total += 38;
+            total += 39;
+            total += 40;
+            defer { log.borrow_mut().push(40); }
+            if total > 0 {
+                total += 1;
+            }
+            for i in 0..2 {
+                total += i;
+            }
+            match total {
+                0 => {}
+                _ => total -= 2,
+            }
+            'outer: loop {
+                break 'outer;
+            }
+            fn triple(n: i32) -> i32 {
+                n * 3
+            }
+            triple(total);
+            if total < 0 {
+                total = 0;
+            } else if total == 0 {
+                total = 1;
+            } else {
+                total = triple(total) / 3;
+            }
+            assert! { total > 0 }
+            let doubled = total * 2;;
+            doubled
+        };
+        assert_eq!(total, 1640);
+        assert_eq!(*log.borrow(), [40, 30, 20, 10]);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_defer_success_1() {
+        let drops = Cell::new(0);
+        {
+            defer_on_success!(drops.set(1));
+            assert_eq!(drops.get(), 0);
+        }
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_defer_success_2() {
+        let drops = Cell::new(0);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            defer_on_success!(drops.set(1));
+            panic!("failure")
+        }));
+        assert_eq!(drops.get(), 0);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_defer_unwind_1() {
+        let drops = Cell::new(0);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            defer_on_unwind!(drops.set(1));
+            assert_eq!(drops.get(), 0);
+            panic!("failure")
+        }));
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_defer_unwind_2() {
+        let drops = Cell::new(0);
+        {
+            defer_on_unwind!(drops.set(1));
+        }
+        assert_eq!(drops.get(), 0);
+    }
+
+    #[test]
+    fn test_only_dropped_by_closure_when_run() {
+        let value_drops = Cell::new(0);
+        let value = guard((), |()| value_drops.set(1 + value_drops.get()));
+        let closure_drops = Cell::new(0);
+        let guard = guard(value, |_| closure_drops.set(1 + closure_drops.get()));
+        assert_eq!(value_drops.get(), 0);
+        assert_eq!(closure_drops.get(), 0);
+        drop(guard);
+        assert_eq!(value_drops.get(), 1);
+        assert_eq!(closure_drops.get(), 1);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_dropped_once_when_not_run() {
+        let value_drops = Cell::new(0);
+        let value = guard((), |()| value_drops.set(1 + value_drops.get()));
+        let captured_drops = Cell::new(0);
+        let captured = guard((), |()| captured_drops.set(1 + captured_drops.get()));
+        let closure_drops = Cell::new(0);
+        let guard = guard_on_unwind(value, |value| {
+            drop(value);
+            drop(captured);
+            closure_drops.set(1 + closure_drops.get())
+        });
+        assert_eq!(value_drops.get(), 0);
+        assert_eq!(captured_drops.get(), 0);
+        assert_eq!(closure_drops.get(), 0);
+        drop(guard);
+        assert_eq!(value_drops.get(), 1);
+        assert_eq!(captured_drops.get(), 1);
+        assert_eq!(closure_drops.get(), 0);
+    }
+
+    #[test]
+    #[allow(clippy::bool_assert_comparison)]
+    fn test_into_inner() {
+        let dropped = Cell::new(false);
+        let value = guard(42, |_| dropped.set(true));
+        let guard = guard(value, |_| dropped.set(true));
+        let inner = ScopeGuard::into_inner(guard);
+        assert_eq!(dropped.get(), false);
+        assert_eq!(*inner, 42);
+    }
+
+    #[test]
+    fn test_cancel() {
+        let dropped = Cell::new(false);
+        let ran = Cell::new(false);
+        let value = guard((), |_| dropped.set(true));
+        ScopeGuard::cancel(guard(value, |_| ran.set(true)));
+        assert!(dropped.get());
+        assert!(!ran.get());
+    }
+
+    #[test]
+    fn test_forget() {
+        let dropped = Cell::new(false);
+        let ran = Cell::new(false);
+        let value = guard((), |_| dropped.set(true));
+        ScopeGuard::forget(guard(value, |_| ran.set(true)));
+        assert!(!dropped.get());
+        assert!(!ran.get());
+    }
+
+    #[test]
+    fn test_into_parts() {
+        let dropped = Cell::new(false);
+        let calls = Cell::new(0);
+        let value = guard((), |_| dropped.set(true));
+        let guard = guard(value, |_| calls.set(calls.get() + 1));
+        let (value, dropfn) = ScopeGuard::into_parts(guard);
+        assert!(!dropped.get());
+        assert_eq!(calls.get(), 0);
+        dropfn(value);
+        assert!(dropped.get());
+        assert_eq!(calls.get(), 1);
+    }
+
+    #[test]
+    fn test_replace() {
+        let result = Cell::new(0);
+        {
+            let mut g = guard(1, |n| result.set(n));
+            assert_eq!(ScopeGuard::replace(&mut g, 2), 1);
+        }
+        assert_eq!(result.get(), 2);
+    }
+
+    #[test]
+    fn test_take() {
+        let seen = Cell::new(None);
+        {
+            let mut g = guard(Some(5), |v| seen.set(Some(v)));
+            assert_eq!(ScopeGuard::take(&mut g), Some(5));
+        }
+        assert_eq!(seen.get(), Some(None));
+    }
+
+    #[test]
+    fn test_swap() {
+        let result = Cell::new(0);
+        let mut other = 2;
+        {
+            let mut g = guard(1, |n| result.set(n));
+            ScopeGuard::swap(&mut g, &mut other);
+        }
+        assert_eq!((result.get(), other), (2, 1));
+    }
+
+    #[test]
+    fn test_disarm_rearm() {
+        let runs = Cell::new(0);
+        {
+            let mut g = guard((), |()| runs.set(runs.get() + 1));
+            ScopeGuard::disarm(&mut g);
+        }
+        {
+            let mut g = guard((), |()| runs.set(runs.get() + 1));
+            ScopeGuard::disarm(&mut g);
+            ScopeGuard::rearm(&mut g);
+            assert!(ScopeGuard::is_armed(&g));
+        }
+        assert_eq!(runs.get(), 1);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_into_strategy() {
+        let runs = Cell::new(0);
+        let g = guard((), |()| runs.set(runs.get() + 1));
+        drop(ScopeGuard::into_strategy::<OnUnwind>(g));
+        let g = guard_on_unwind((), |()| runs.set(runs.get() + 10));
+        drop(ScopeGuard::into_strategy::<Always>(g));
+        assert_eq!(runs.get(), 10);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_as_pinned_mut_drops_in_place() {
+        use std::marker::PhantomPinned;
+
+        struct Node<'a> {
+            address: &'a Cell<usize>,
+            _pinned: PhantomPinned,
+        }
+
+        impl<'a> Node<'a> {
+            fn register(self: Pin<&mut Self>) {
+                self.address.set(&*self as *const Self as usize);
+            }
+        }
+
+        impl<'a> Drop for Node<'a> {
+            fn drop(&mut self) {
+                assert_eq!(self.address.get(), self as *const Self as usize);
+            }
+        }
+
+        let address = Cell::new(0);
+        let mut g = Box::pin(guard_on_unwind(
+            Node {
+                address: &address,
+                _pinned: PhantomPinned,
+            },
+            |_| panic!("not unwinding"),
+        ));
+        unsafe { ScopeGuard::as_pinned_mut(g.as_mut()) }.register();
+        assert_ne!(address.get(), 0);
+        drop(g);
+    }
+
+    #[test]
+    fn test_closure_mut() {
+        fn unexpected(_: u32) {
+            panic!("the closure was replaced");
+        }
+
+        let mut g = guard(1, unexpected as fn(u32));
+        *ScopeGuard::closure_mut(&mut g) = drop;
+        let dropfn: &fn(u32) = ScopeGuard::closure_ref(&g);
+        dropfn(*g);
+    }
+
+    #[test]
+    fn test_clone() {
+        use std::sync::atomic::AtomicUsize;
+
+        let runs = AtomicUsize::new(0);
+        let g = guard(1, |n| {
+            runs.fetch_add(n, Ordering::SeqCst);
+        });
+        let mut disarmed = g.clone();
+        ScopeGuard::disarm(&mut disarmed);
+        let mut clone = disarmed.clone();
+        ScopeGuard::rearm(&mut clone);
+        *clone = 10;
+        drop((g, disarmed, clone));
+        assert_eq!(runs.load(Ordering::SeqCst), 11);
+    }
+
+    #[test]
+    fn test_comparisons() {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::{Hash, Hasher};
+
+        fn hash<T: Hash>(value: &T) -> u64 {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        }
+
+        let a = guard(1, |_| {});
+        let b = guard_with::<Always, _, _>(1, drop);
+        let c = guard(2, |_| {});
+        assert!(a == b);
+        assert!(a != c);
+        assert!(b < c);
+        assert_eq!(b.cmp(&b), std::cmp::Ordering::Equal);
+        assert_eq!(hash(&a), hash(&1));
+    }
+
+    #[test]
+    fn test_display() {
+        let g = guard(1.5, |_| {});
+        assert_eq!(format!("{:>5}", g), "  1.5");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serialize() {
+        use serde_test::{assert_ser_tokens, Token};
+
+        let session = guard(Some("user"), |_| {});
+        assert_ser_tokens(&session, &[Token::Some, Token::Str("user")]);
+    }
+
+    #[test]
+    fn test_guard_with_context() {
+        let seen = Cell::new((0, 0));
+        let mut g = guard_with_context(1, 2, |v, ctx| seen.set((v, ctx)));
+        *g += 10;
+        drop(g);
+        assert_eq!(seen.get(), (11, 2));
+    }
+
+    #[test]
+    fn test_guard_mut() {
+        struct Record<'a>(&'a RefCell<Vec<&'static str>>);
+
+        impl<'a> Drop for Record<'a> {
+            fn drop(&mut self) {
+                self.0.borrow_mut().push("drop");
+            }
+        }
+
+        let log = RefCell::new(Vec::new());
+        drop(guard_mut(Record(&log), |r| {
+            r.0.borrow_mut().push("cleanup")
+        }));
+        assert_eq!(*log.borrow(), ["cleanup", "drop"]);
+    }
+
+    #[test]
+    fn test_guard_ref() {
+        let mut text = String::from("a");
+        {
+            let mut g = guard_ref(&mut text, |t| t.push('!'));
+            g.push('b');
+            **g += "c";
+        }
+        assert_eq!(text, "abc!");
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_guard_boxed() {
+        use std::sync::atomic::AtomicUsize;
+
+        let runs = AtomicUsize::new(0);
+        let add = |n| {
+            let runs = &runs;
+            move |()| {
+                runs.fetch_add(n, Ordering::SeqCst);
+            }
+        };
+        let guards: Vec<BoxedGuard<()>> = vec![guard_boxed((), add(1)), guard_boxed((), add(10))];
+        drop(guards);
+        assert_eq!(runs.load(Ordering::SeqCst), 11);
+    }
 
+    #[cfg(feature = "alloc")]
     #[test]
-    fn test_defer() {
+    fn test_guard_dyn() {
+        use std::sync::atomic::AtomicBool;
+
+        static RAN: AtomicBool = AtomicBool::new(false);
+        struct Holder {
+            cleanup: Option<DynGuard>,
+        }
+
+        let mut holder = Holder { cleanup: None };
+        holder.cleanup = Some(guard_dyn(|| RAN.store(true, Ordering::SeqCst)));
+        assert!(!RAN.load(Ordering::SeqCst));
+        drop(holder);
+        assert!(RAN.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn test_debug() {
+        let mut g = guard(5, |_| {});
+        ScopeGuard::disarm(&mut g);
+        assert_eq!(
+            format!("{:?}", g),
+            "ScopeGuard { value: 5, strategy: scopeguard::Always, armed: false }"
+        );
+    }
+
+    #[test]
+    fn test_guard_try() {
+        let error = Cell::new(None);
+        drop(guard_try(1, |_| Ok::<(), i32>(()), |e| error.set(Some(e))));
+        assert_eq!(error.get(), None);
+        drop(guard_try(2, Err, |e| error.set(Some(e))));
+        assert_eq!(error.get(), Some(2));
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_guard_catch_unwind() {
+        let caught = Cell::new(None);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let _g = guard_catch_unwind(
+                (),
+                |()| panic!("second"),
+                |payload| caught.set(payload.downcast_ref::<&str>().cloned()),
+            );
+            panic!("first");
+        }));
+        let payload = result.unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"first"));
+        assert_eq!(caught.get(), Some("second"));
+
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            drop(guard_catch_unwind((), |()| panic!("cleanup"), |_| {}));
+        }));
+        assert!(result.is_err());
+    }
+
+    #[cfg(feature = "use_std")]
+    #[cfg_attr(miri, ignore = "spawns a process")]
+    #[test]
+    fn test_guard_abort_on_panic() {
+        use std::env;
+        use std::process::Command;
+
+        const TRIGGER: &str = "SCOPEGUARD_TEST_ABORT_ON_PANIC";
+        if env::var_os(TRIGGER).is_some() {
+            let _g = guard_abort_on_panic((), |()| panic!("cleanup failed"));
+            return;
+        }
+
+        let ran = Cell::new(false);
+        drop(guard_abort_on_panic((), |()| ran.set(true)));
+        assert!(ran.get());
+
+        let output = Command::new(env::current_exe().unwrap())
+            .arg("--exact")
+            .arg("tests::test_guard_abort_on_panic")
+            .arg("--nocapture")
+            .env(TRIGGER, "1")
+            .output()
+            .unwrap();
+        assert!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(
+            stderr.contains("cleanup closure panicked, aborting"),
+            "{}",
+            stderr
+        );
+    }
+
+    #[test]
+    fn test_stateful_strategy() {
+        struct Flag(bool);
+
+        impl Strategy for Flag {
+            fn should_run(&self) -> bool {
+                self.0
+            }
+        }
+
+        let runs = Cell::new(0);
+        let mut g = ScopeGuard::new((), |()| runs.set(runs.get() + 1), Flag(false));
+        drop(
+            builder(())
+                .with_strategy(Flag(true))
+                .build(|()| runs.set(runs.get() + 10)),
+        );
+        assert_eq!(runs.get(), 10);
+        assert!(!ScopeGuard::strategy(&g).0);
+        ScopeGuard::strategy_mut(&mut g).0 = true;
+        drop(g);
+        assert_eq!(runs.get(), 11);
+    }
+
+    #[test]
+    fn test_strategy_panic_drops_value() {
+        struct Panics;
+
+        impl Strategy for Panics {
+            fn should_run(&self) -> bool {
+                panic!("strategy failed");
+            }
+        }
+
+        struct CountDrops<'a>(&'a Cell<u32>);
+
+        impl<'a> Drop for CountDrops<'a> {
+            fn drop(&mut self) {
+                self.0.set(self.0.get() + 1);
+            }
+        }
+
         let drops = Cell::new(0);
-        defer!(drops.set(1000));
-        assert_eq!(drops.get(), 0);
+        let ran = Cell::new(false);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            let closure_drop = CountDrops(&drops);
+            let ran = &ran;
+            let _g = ScopeGuard::new(
+                CountDrops(&drops),
+                move |_| {
+                    let _closure_drop = closure_drop;
+                    ran.set(true);
+                },
+                Panics,
+            );
+        }));
+        assert!(result.is_err());
+        assert!(!ran.get());
+        assert_eq!(drops.get(), 2);
+    }
+
+    #[test]
+    fn test_guard_if() {
+        let runs = Cell::new(0);
+        drop(guard_if(false, 1, |n| runs.set(runs.get() + n)));
+        drop(guard_if(true, 10, |n| runs.set(runs.get() + n)));
+        let mut g = guard_if(false, 100, |n| runs.set(runs.get() + n));
+        *ScopeGuard::strategy_mut(&mut g) = WhenFlag(true);
+        drop(g);
+        assert_eq!(runs.get(), 110);
+    }
+
+    #[test]
+    fn test_strategy_combinators() {
+        let (t, f) = (WhenFlag(true), WhenFlag(false));
+        assert!(Not(f).should_run());
+        assert!(!Not(t).should_run());
+        assert!(And(t, t).should_run());
+        assert!(!And(t, f).should_run());
+        assert!(Or(f, t).should_run());
+        assert!(!Or(f, f).should_run());
+
+        let runs = Cell::new(0);
+        drop(ScopeGuard::new(
+            (),
+            |()| runs.set(runs.get() + 1),
+            And(Always, Not(f)),
+        ));
+        drop(ScopeGuard::new(
+            (),
+            |()| runs.set(runs.get() + 10),
+            Or(f, Not(Always)),
+        ));
+        assert_eq!(runs.get(), 1);
+    }
+
+    #[test]
+    fn test_guard_with_fn() {
+        let runs = Cell::new(0);
+        let committed = Cell::new(false);
+        drop(guard_with_fn(
+            1,
+            |n| runs.set(runs.get() + n),
+            || !committed.get(),
+        ));
+        committed.set(true);
+        drop(guard_with_fn(
+            10,
+            |n| runs.set(runs.get() + n),
+            || !committed.get(),
+        ));
+        assert_eq!(runs.get(), 1);
+        let strategy = WhenFn::new(|| true);
+        assert!(strategy.should_run());
+        assert!(!strategy.should_run());
     }
 
     #[cfg(feature = "use_std")]
     #[test]
-    fn test_defer_success_1() {
+    fn test_on_new_unwind() {
+        struct DropLogger<'a>(&'a Cell<u32>);
+        impl<'a> Drop for DropLogger<'a> {
+            fn drop(&mut self) {
+                let runs = self.0;
+                let _old = guard_with::<OnUnwind, _, _>((), |()| runs.set(runs.get() + 1));
+                let _new = guard_with::<OnNewUnwind, _, _>((), |()| runs.set(runs.get() + 10));
+            }
+        }
+        let runs = Cell::new(0);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _logger = DropLogger(&runs);
+            panic!("dropping the logger while unwinding");
+        }));
+        assert_eq!(runs.get(), 1);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _g = guard_with::<OnNewUnwind, _, _>((), |()| runs.set(runs.get() + 100));
+            panic!();
+        }));
+        assert_eq!(runs.get(), 101);
+    }
+
+    #[test]
+    fn test_guard_when() {
+        let runs = Cell::new(0);
+        drop(guard_when(1, |n| runs.set(runs.get() + n), |&n| n > 5));
+        let mut g = guard_when(1, |n| runs.set(runs.get() + n), |&n| n > 5);
+        *g = 10;
+        drop(g);
+        assert_eq!(runs.get(), 10);
+    }
+
+    #[test]
+    fn test_when_atomic_flag() {
+        static FLAG: AtomicBool = AtomicBool::new(false);
+        let runs = Cell::new(0);
+        let g = ScopeGuard::new((), |()| runs.set(runs.get() + 1), WhenAtomicFlag(&FLAG));
+        drop(g);
+        let g = ScopeGuard::new((), |()| runs.set(runs.get() + 10), WhenAtomicFlag(&FLAG));
+        FLAG.store(true, Ordering::SeqCst);
+        drop(g);
+        assert_eq!(runs.get(), 10);
+    }
+
+    #[test]
+    fn test_never() {
+        struct Dropper<'a>(&'a Cell<u32>);
+        impl<'a> Drop for Dropper<'a> {
+            fn drop(&mut self) {
+                self.0.set(1);
+            }
+        }
         let drops = Cell::new(0);
-        {
-            defer_on_success!(drops.set(1));
-            assert_eq!(drops.get(), 0);
+        let runs = Cell::new(0);
+        drop(guard_with::<Never, _, _>(Dropper(&drops), |_| runs.set(1)));
+        assert_eq!((runs.get(), drops.get()), (0, 1));
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_guard_dyn_strategy() {
+        let runs = Cell::new(0);
+        let strategies = [
+            RuntimeStrategy::Always,
+            RuntimeStrategy::OnSuccess,
+            RuntimeStrategy::OnUnwind,
+            RuntimeStrategy::Never,
+        ];
+        for (i, &strategy) in strategies.iter().enumerate() {
+            drop(guard_dyn_strategy(
+                1 << i,
+                |n| runs.set(runs.get() | n),
+                strategy,
+            ));
         }
-        assert_eq!(drops.get(), 1);
+        assert_eq!(runs.get(), 0b11);
+        for (i, &strategy) in strategies.iter().enumerate() {
+            let _ = catch_unwind(AssertUnwindSafe(|| {
+                let _g = guard_dyn_strategy(1 << (i + 4), |n| runs.set(runs.get() | n), strategy);
+                panic!();
+            }));
+        }
+        assert_eq!(runs.get(), 0b0101_0011);
     }
 
     #[cfg(feature = "use_std")]
     #[test]
-    fn test_defer_success_2() {
-        let drops = Cell::new(0);
+    fn test_parse_env_cleanup() {
+        use std::ffi::OsStr;
+
+        assert_eq!(parse_env_cleanup(None), 1);
+        assert_eq!(parse_env_cleanup(Some(OsStr::new(""))), 1);
+        assert_eq!(parse_env_cleanup(Some(OsStr::new("skip"))), 2);
+        assert_eq!(parse_env_cleanup(Some(OsStr::new("force"))), 3);
+        assert_eq!(parse_env_cleanup(Some(OsStr::new("Force"))), 1);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[cfg_attr(miri, ignore = "spawns a process")]
+    #[test]
+    fn test_env_override() {
+        use std::env;
+        use std::process::Command;
+
+        // The variable is only read once, so test each value in a new process.
+        const EXPECTED: &str = "SCOPEGUARD_TEST_ENV_OVERRIDE";
+        if let Ok(expected) = env::var(EXPECTED) {
+            let runs = Cell::new(0);
+            drop(guard_with::<EnvOverride<Never>, _, _>((), |()| runs.set(1)));
+            assert_eq!(runs.get().to_string(), expected);
+            return;
+        }
+
+        for &(cleanup, expected) in &[("skip", "0"), ("force", "1"), ("", "0")] {
+            let output = Command::new(env::current_exe().unwrap())
+                .arg("--exact")
+                .arg("tests::test_env_override")
+                .env(EXPECTED, expected)
+                .env("SCOPEGUARD_CLEANUP", cleanup)
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "SCOPEGUARD_CLEANUP={}", cleanup);
+        }
+    }
+
+    #[cfg(feature = "allocator_api")]
+    #[test]
+    fn test_guard_boxed_in() {
+        use std::alloc::{AllocError, Layout};
+        use std::ptr::NonNull;
+        use std::sync::atomic::AtomicUsize;
+
+        // Counts the live allocations made through it.
+        struct Counting(Cell<usize>);
+
+        unsafe impl Allocator for Counting {
+            fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+                self.0.set(self.0.get() + 1);
+                Global.allocate(layout)
+            }
+
+            unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+                self.0.set(self.0.get() - 1);
+                Global.deallocate(ptr, layout)
+            }
+        }
+
+        static DYN_RUNS: AtomicUsize = AtomicUsize::new(0);
+        let arena = Counting(Cell::new(0));
+        let runs = AtomicUsize::new(0);
+        let local_runs = Cell::new(0);
+        {
+            let _boxed = guard_boxed_in(
+                1,
+                |n| {
+                    runs.fetch_add(n, Ordering::SeqCst);
+                },
+                &arena,
+            );
+            let _local = ScopeGuard::into_local_boxed_in(
+                guard(10, |n| local_runs.set(local_runs.get() + n)),
+                &arena,
+            );
+            let _dyn: DynGuard<&Counting> = guard_dyn_in(
+                || {
+                    DYN_RUNS.fetch_add(1, Ordering::SeqCst);
+                },
+                &arena,
+            );
+            assert!(arena.0.get() >= 1);
+        }
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(local_runs.get(), 10);
+        assert_eq!(DYN_RUNS.load(Ordering::SeqCst), 1);
+        assert_eq!(arena.0.get(), 0);
+    }
+
+    #[test]
+    fn test_const_constructors() {
+        use std::sync::atomic::AtomicUsize;
+
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        fn count(n: usize) {
+            RUNS.fetch_add(n, Ordering::SeqCst);
+        }
+        const ONE: ScopeGuard<usize, fn(usize)> = guard(1, count);
+        const TEN: ScopeGuard<usize, fn(usize)> = ScopeGuard::new(10, count, Always);
+
+        drop(ONE);
+        drop(TEN);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 11);
+    }
+
+    #[test]
+    fn test_map() {
+        let result = Cell::new(0);
+        let g = guard(1, |n| result.set(n));
+        let g = ScopeGuard::map(g, |n| n * 10, |dropfn| move |n| dropfn(n + 1));
+        assert_eq!(*g, 10);
+        drop(g);
+        assert_eq!(result.get(), 11);
+    }
+
+    #[test]
+    fn test_and_then_runs_original_after_panic() {
+        let ran = Cell::new(false);
         let _ = catch_unwind(AssertUnwindSafe(|| {
-            defer_on_success!(drops.set(1));
-            panic!("failure")
+            let g = guard((), |()| ran.set(true));
+            let _g = ScopeGuard::and_then(g, |_| panic!("extra step failed"));
         }));
-        assert_eq!(drops.get(), 0);
+        assert!(ran.get());
     }
 
     #[cfg(feature = "use_std")]
     #[test]
-    fn test_defer_unwind_1() {
+    fn test_run_now_ignores_strategy() {
+        let ran = Cell::new(false);
+        ScopeGuard::run_now(guard_on_unwind((), |()| ran.set(true)));
+        assert!(ran.get());
+    }
+
+    #[test]
+    fn test_run_now_disarmed() {
+        let ran = Cell::new(false);
+        let mut g = guard((), |()| ran.set(true));
+        ScopeGuard::disarm(&mut g);
+        ScopeGuard::run_now(g);
+        assert!(!ran.get());
+    }
+
+    #[test]
+    fn test_accessors_do_not_collide_with_value_methods() {
+        struct Shadow;
+        impl Shadow {
+            fn get_ref(&self) -> u8 {
+                1
+            }
+            fn take(&mut self) -> u8 {
+                2
+            }
+        }
+        let mut g = guard(Shadow, |_| ());
+        assert_eq!(g.get_ref(), 1);
+        assert_eq!(g.take(), 2);
+        assert_eq!(ScopeGuard::get_ref(&g).get_ref(), 1);
+        assert_eq!(ScopeGuard::get_mut(&mut g).take(), 2);
+    }
+
+    #[test]
+    fn test_get_ref_get_mut() {
+        let dropped = Cell::new(0);
+        let mut guard = guard(vec![1], |v| dropped.set(v.len()));
+        ScopeGuard::get_mut(&mut guard).push(2);
+        assert_eq!(ScopeGuard::get_ref(&guard), &[1, 2]);
+        drop(guard);
+        assert_eq!(dropped.get(), 2);
+    }
+
+    #[test]
+    fn test_guard3() {
+        let sum = Cell::new(0);
+        {
+            let mut g = guard3(1, 2, 3, |a, b, c| sum.set(a + b + c));
+            g.2 = 30;
+            assert_eq!(*g, (1, 2, 30));
+        }
+        assert_eq!(sum.get(), 33);
+    }
+
+    #[test]
+    fn test_from_tuple() {
+        let dropped = Cell::new(false);
+        let guard: ScopeGuard<_, _> = (5, |_| dropped.set(true)).into();
+        assert_eq!(*guard, 5);
+        drop(guard);
+        assert!(dropped.get());
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_retry_with_cleanup_gives_up() {
+        let tries = Cell::new(0);
+        let resets = Cell::new(0);
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            retry_with_cleanup(
+                2,
+                || -> Result<(), ()> {
+                    tries.set(tries.get() + 1);
+                    panic!("failure")
+                },
+                || resets.set(resets.get() + 1),
+            )
+        }));
+        assert!(result.is_err());
+        assert_eq!(tries.get(), 2);
+        assert_eq!(resets.get(), 2);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_thread_override() {
         let drops = Cell::new(0);
+        std::thread::spawn(|| {
+            OnSuccess::set_thread_override(Some(false));
+            let _g = guard_on_success((), |_| panic!("disabled on this thread"));
+        })
+        .join()
+        .unwrap();
+        drop(guard_on_success((), |_| drops.set(1)));
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[test]
+    fn test_defer_try_catch() {
+        let caught = Cell::new(None);
+        {
+            defer!(try {
+                let _: u8 = "300".parse()?;
+            } catch |e: std::num::ParseIntError| caught.set(Some(e)));
+        }
+        assert!(caught.take().is_some());
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_guard_watchdog() {
+        let pets = Cell::new(0);
+        drop(guard_watchdog::<Always, _>(|| pets.set(pets.get() + 1)));
         let _ = catch_unwind(AssertUnwindSafe(|| {
-            defer_on_unwind!(drops.set(1));
-            assert_eq!(drops.get(), 0);
-            panic!("failure")
+            let _pet = guard_watchdog::<OnSuccess, _>(|| pets.set(pets.get() + 10));
+            panic!("stuck");
         }));
+        assert_eq!(pets.get(), 1);
+    }
+
+    #[test]
+    fn test_maybe_guard() {
+        let drops = Cell::new(0);
+        drop(maybe_guard(true, (), |()| drops.set(drops.get() + 1)));
+        drop(maybe_guard(false, (), |()| drops.set(drops.get() + 10)));
         assert_eq!(drops.get(), 1);
     }
 
     #[cfg(feature = "use_std")]
     #[test]
-    fn test_defer_unwind_2() {
+    fn test_catch_unwind_guarded() {
+        let restored = Cell::new(false);
+        let result = catch_unwind_guarded(|| {
+            let _g = guard(&restored, |r| r.set(true));
+            panic!("interrupted");
+        });
+        assert!(result.is_err());
+        assert!(restored.get());
+        assert_eq!(catch_unwind_guarded(|| 1).ok(), Some(1));
+    }
+
+    #[test]
+    fn test_guards_macro() {
+        let order = RefCell::new(Vec::new());
+        {
+            guards! {
+                _first = (1, |n| order.borrow_mut().push(n));
+                mut second = (2, |n| order.borrow_mut().push(n));
+            }
+            *second += 1;
+        }
+        assert_eq!(*order.borrow(), [3, 1]);
+    }
+
+    #[test]
+    fn test_guard_in_category() {
+        static CATEGORY: Category = Category::new("test");
         let drops = Cell::new(0);
         {
-            defer_on_unwind!(drops.set(1));
+            let _a = guard_in(&CATEGORY, (), |()| drops.set(drops.get() + 1));
+            let _b = guard_in(&CATEGORY, (), |()| drops.set(drops.get() + 1));
+            set_enabled(&CATEGORY, false);
+            drop(_b);
+            set_enabled(&CATEGORY, true);
         }
-        assert_eq!(drops.get(), 0);
+        assert_eq!(drops.get(), 1);
     }
 
+    #[cfg(all(feature = "use_std", feature = "metrics"))]
     #[test]
-    fn test_only_dropped_by_closure_when_run() {
-        let value_drops = Cell::new(0);
-        let value = guard((), |()| value_drops.set(1 + value_drops.get()));
-        let closure_drops = Cell::new(0);
-        let guard = guard(value, |_| closure_drops.set(1 + closure_drops.get()));
-        assert_eq!(value_drops.get(), 0);
-        assert_eq!(closure_drops.get(), 0);
-        drop(guard);
-        assert_eq!(value_drops.get(), 1);
-        assert_eq!(closure_drops.get(), 1);
+    fn test_guard_timed_histogram() {
+        use metrics::{
+            Counter, Gauge, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder, SharedString,
+            Unit,
+        };
+        use std::sync::{Arc, Mutex};
+
+        #[derive(Default)]
+        struct Samples(Mutex<Vec<(String, f64)>>);
+
+        struct Handle(Arc<Samples>, String);
+
+        impl HistogramFn for Handle {
+            fn record(&self, value: f64) {
+                self.0 .0.lock().unwrap().push((self.1.clone(), value));
+            }
+        }
+
+        struct Recording(Arc<Samples>);
+
+        impl Recorder for Recording {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn register_counter(&self, _: &Key, _: &Metadata<'_>) -> Counter {
+                Counter::noop()
+            }
+            fn register_gauge(&self, _: &Key, _: &Metadata<'_>) -> Gauge {
+                Gauge::noop()
+            }
+            fn register_histogram(&self, key: &Key, _: &Metadata<'_>) -> Histogram {
+                let handle = Handle(self.0.clone(), key.name().to_owned());
+                Histogram::from_arc(Arc::new(handle))
+            }
+        }
+
+        let samples = Arc::new(Samples::default());
+        metrics::with_local_recorder(&Recording(samples.clone()), || {
+            let _g = guard_timed_histogram("cleanup", (), |()| {
+                std::thread::sleep(std::time::Duration::from_millis(1));
+            });
+        });
+        let samples = samples.0.lock().unwrap();
+        assert_eq!(samples.len(), 1);
+        assert_eq!(samples[0].0, "cleanup");
+        assert!(samples[0].1 >= 0.001);
     }
 
     #[cfg(feature = "use_std")]
     #[test]
-    fn test_dropped_once_when_not_run() {
-        let value_drops = Cell::new(0);
-        let value = guard((), |()| value_drops.set(1 + value_drops.get()));
-        let captured_drops = Cell::new(0);
-        let captured = guard((), |()| captured_drops.set(1 + captured_drops.get()));
-        let closure_drops = Cell::new(0);
-        let guard = guard_on_unwind(value, |value| {
-            drop(value);
-            drop(captured);
-            closure_drops.set(1 + closure_drops.get())
-        });
-        assert_eq!(value_drops.get(), 0);
-        assert_eq!(captured_drops.get(), 0);
-        assert_eq!(closure_drops.get(), 0);
-        drop(guard);
-        assert_eq!(value_drops.get(), 1);
-        assert_eq!(captured_drops.get(), 1);
-        assert_eq!(closure_drops.get(), 0);
+    fn test_guard_timed_records_on_panic() {
+        let recorded = Cell::new(false);
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _g = guard_timed((), |()| panic!("cleanup failed"), |_| recorded.set(true));
+        }));
+        assert!(recorded.get());
     }
 
+    #[cfg(feature = "use_std")]
     #[test]
-    fn test_into_inner() {
-        let dropped = Cell::new(false);
-        let value = guard(42, |_| dropped.set(true));
-        let guard = guard(value, |_| dropped.set(true));
-        let inner = ScopeGuard::into_inner(guard);
-        assert_eq!(dropped.get(), false);
-        assert_eq!(*inner, 42);
+    fn test_guard_non_reentrant() {
+        static ID: NonReentrant = NonReentrant::new("test");
+        fn cleanup(depth: &Cell<u32>) {
+            let _g = guard_non_reentrant(&ID, (), |()| {
+                depth.set(depth.get() + 1);
+                cleanup(depth);
+            });
+        }
+        let depth = Cell::new(0);
+        cleanup(&depth);
+        assert_eq!(depth.get(), 1);
+        assert!(!ID.is_running());
+        cleanup(&depth);
+        assert_eq!(depth.get(), 2);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_guard_non_reentrant_reports_reentry() {
+        thread_local! {
+            static SKIPPED: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) };
+        }
+        static ID: NonReentrant = NonReentrant::new("report")
+            .on_reentry(|id| SKIPPED.with(|s| s.borrow_mut().push(id.name())));
+        fn cleanup(depth: &Cell<u32>) {
+            let _g = guard_non_reentrant(&ID, (), |()| {
+                depth.set(depth.get() + 1);
+                cleanup(depth);
+            });
+        }
+        let depth = Cell::new(0);
+        cleanup(&depth);
+        assert_eq!(depth.get(), 1);
+        SKIPPED.with(|s| assert_eq!(*s.borrow(), ["report"]));
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_guard_non_reentrant_in_thread_local_destructor() {
+        use std::sync::mpsc;
+
+        static ID: NonReentrant = NonReentrant::new("thread exit");
+        thread_local! {
+            static HOLDER: RefCell<Option<Box<dyn std::any::Any>>> = const { RefCell::new(None) };
+        }
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            let g = guard_non_reentrant(&ID, tx, |tx| tx.send(()).unwrap());
+            HOLDER.with(|h| *h.borrow_mut() = Some(Box::new(g)));
+            // Register the list of running cleanups after `HOLDER`, so that it
+            // is usually destroyed first.
+            drop(guard_non_reentrant(&ID, (), |()| {}));
+        })
+        .join()
+        .unwrap();
+        assert!(rx.recv().is_ok());
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_guard_within() {
+        use std::time::Duration;
+
+        let drops = Cell::new(0);
+        drop(guard_within(Duration::from_secs(3600), 1, |v| drops.set(v)));
+        assert_eq!(drops.get(), 1);
+        let expired = guard_within(Duration::from_millis(1), 2, |v| drops.set(v));
+        std::thread::sleep(Duration::from_millis(10));
+        drop(expired);
+        assert_eq!(drops.get(), 1);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_builder() {
+        let drops = Cell::new(0);
+        {
+            let guard = builder(5)
+                .on_success()
+                .named("five")
+                .build(|v| drops.set(v));
+            if cfg!(feature = "debug_info") {
+                assert!(format!("{:?}", guard).contains("five"));
+            }
+        }
+        assert_eq!(drops.get(), 5);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn test_into_boxed() {
+        let drops = Cell::new(0);
+        let mut guards: Vec<LocalBoxedGuard<i32>> = Vec::new();
+        guards.push(ScopeGuard::into_local_boxed(guard(1, |v| {
+            drops.set(drops.get() + v)
+        })));
+        guards.push(ScopeGuard::into_local_boxed(guard(2, |v| {
+            drops.set(drops.get() + v * 10)
+        })));
+        assert_eq!(drops.get(), 0);
+        drop(guards);
+        assert_eq!(drops.get(), 21);
     }
 }
@@ -0,0 +1,73 @@
+//! Panic-count bookkeeping for `no_std` programs that unwind.
+//!
+//! Without `std`, this crate cannot ask whether the current thread is
+//! unwinding. A program built with `panic = "unwind"` can instead report it
+//! here: call [`enter`](fn.enter.html) when a panic starts unwinding, for
+//! example from its `#[panic_handler]` before it starts the unwinder, and
+//! [`exit`](fn.exit.html) when the panic is caught.
+//!
+//! Without crate feature `use_std` and without a hook installed with
+//! [`set_panicking_hook`](../fn.set_panicking_hook.html), the
+//! [`OnUnwind`](../struct.OnUnwind.html) and
+//! [`OnSuccess`](../struct.OnSuccess.html) strategies consider the program to
+//! be unwinding while the count is not zero. In programs built with
+//! `panic = "abort"`, they ignore it, since nothing can unwind.
+//!
+//! The count is global, not per thread, so it is meant for single-threaded
+//! targets.
+//!
+//! ```
+//! extern crate scopeguard;
+//!
+//! use scopeguard::panic_count;
+//!
+//! fn main() {
+//!     panic_count::enter();
+//!     assert_eq!(panic_count::get(), 1);
+//!     panic_count::exit();
+//!     assert_eq!(panic_count::get(), 0);
+//! }
+//! ```
+//!
+//! Requires a target with atomic pointers.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+static PANIC_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Record that a panic started unwinding.
+pub fn enter() {
+    PANIC_COUNT.fetch_add(1, Ordering::SeqCst);
+}
+
+/// Record that a panic was caught and stopped unwinding.
+///
+/// ***Panics*** if the count is already zero.
+pub fn exit() {
+    let decremented =
+        PANIC_COUNT.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1));
+    if decremented.is_err() {
+        panic!("panic_count::exit called without a matching enter");
+    }
+}
+
+/// Return the number of panics that are unwinding.
+pub fn get() -> usize {
+    PANIC_COUNT.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unbalanced_exit() {
+        // The count is global, so only check what other tests cannot change.
+        if get() == 0 {
+            assert!(::std::panic::catch_unwind(exit).is_err());
+        }
+        enter();
+        assert!(get() >= 1);
+        exit();
+    }
+}
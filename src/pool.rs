@@ -0,0 +1,121 @@
+//! Guards that return checked out values to their pool.
+
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+#[cfg(feature = "alloc")]
+use std::cell::RefCell;
+
+use guard;
+#[cfg(feature = "use_std")]
+use guard_on_success;
+#[cfg(feature = "use_std")]
+use OnSuccess;
+use ScopeGuard;
+
+/// A pool that values can be given back to.
+///
+/// Implement it for a connection or buffer pool to get guards that return
+/// checked out values with [`checkout`](fn.checkout.html).
+pub trait Pool<T> {
+    /// Return `value` to the pool.
+    fn give_back(&self, value: T);
+}
+
+impl<T, P> Pool<T> for &P
+where
+    P: Pool<T> + ?Sized,
+{
+    fn give_back(&self, value: T) {
+        (**self).give_back(value)
+    }
+}
+
+/// A simple pool: values are pushed back onto the vector.
+///
+/// Requires crate feature `alloc`.
+#[cfg(feature = "alloc")]
+impl<T> Pool<T> for RefCell<Vec<T>> {
+    fn give_back(&self, value: T) {
+        self.borrow_mut().push(value)
+    }
+}
+
+/// A simple pool: values are pushed back onto the vector.
+///
+/// A poisoned lock is ignored.
+///
+/// Requires crate feature `use_std`.
+#[cfg(feature = "use_std")]
+impl<T> Pool<T> for std::sync::Mutex<Vec<T>> {
+    fn give_back(&self, value: T) {
+        self.lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .push(value)
+    }
+}
+
+/// Create a guard that gives `value` back to `pool` on scope exit, including
+/// during unwinding.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::RefCell;
+/// use scopeguard::pool::{checkout, Pool};
+///
+/// struct Buffers(RefCell<Vec<Vec<u8>>>);
+///
+/// impl Pool<Vec<u8>> for Buffers {
+///     fn give_back(&self, mut buffer: Vec<u8>) {
+///         buffer.clear();
+///         self.0.borrow_mut().push(buffer);
+///     }
+/// }
+///
+/// fn main() {
+///     let buffers = Buffers(RefCell::new(vec![Vec::with_capacity(1024)]));
+///     {
+///         let buffer = buffers.0.borrow_mut().pop().unwrap();
+///         let mut buffer = checkout(&buffers, buffer);
+///         buffer.extend_from_slice(b"data");
+///     }
+///     assert_eq!(buffers.0.borrow().len(), 1);
+/// }
+/// ```
+pub fn checkout<T, P>(pool: P, value: T) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    P: Pool<T>,
+{
+    guard(value, move |value| pool.give_back(value))
+}
+
+/// Create a guard that gives `value` back to `pool` on scope exit, but not
+/// during unwinding, where the value may be in an inconsistent state and is
+/// dropped instead.
+///
+/// Requires crate feature `use_std`.
+#[cfg(feature = "use_std")]
+pub fn checkout_on_success<T, P>(pool: P, value: T) -> ScopeGuard<T, impl FnOnce(T), OnSuccess>
+where
+    P: Pool<T>,
+{
+    guard_on_success(value, move |value| pool.give_back(value))
+}
+
+#[cfg(all(test, feature = "use_std"))]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[test]
+    fn test_checkout_on_success() {
+        let pool = RefCell::new(Vec::new());
+        drop(checkout_on_success(&pool, 1));
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let _conn = checkout_on_success(&pool, 2);
+            panic!("connection broken");
+        }));
+        drop(checkout(&pool, 3));
+        assert_eq!(*pool.borrow(), [1, 3]);
+    }
+}
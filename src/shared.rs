@@ -0,0 +1,365 @@
+//! Guards shared by several owners, whose closure runs when the last owner
+//! goes away: [`SharedGuard`](struct.SharedGuard.html) for several threads
+//! and [`LocalSharedGuard`](struct.LocalSharedGuard.html) for one.
+//!
+//! Requires crate feature `alloc`.
+
+use alloc::rc::Rc;
+#[cfg(target_has_atomic = "ptr")]
+use alloc::sync::Arc;
+use std::cell::Cell;
+use std::fmt;
+use std::ops::Deref;
+#[cfg(target_has_atomic = "ptr")]
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use Always;
+use ScopeGuard;
+use Strategy;
+
+/// A scope guard shared through an `Arc`: cloning it adds an owner, and the
+/// closure runs once, when the last owner is dropped.
+///
+/// The strategy is evaluated on the thread that drops the last owner.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use std::thread;
+/// use scopeguard::shared::SharedGuard;
+///
+/// static REMOVED: AtomicBool = AtomicBool::new(false);
+///
+/// fn main() {
+///     let tmp_dir = SharedGuard::new("/tmp/job-42", |_dir| {
+///         // std::fs::remove_dir_all(dir)
+///         REMOVED.store(true, Ordering::SeqCst);
+///     });
+///     let workers: Vec<_> = (0..4)
+///         .map(|_| {
+///             let tmp_dir = tmp_dir.clone();
+///             thread::spawn(move || assert_eq!(*tmp_dir, "/tmp/job-42"))
+///         })
+///         .collect();
+///     drop(tmp_dir);
+///     for worker in workers {
+///         worker.join().unwrap();
+///     }
+///     assert!(REMOVED.load(Ordering::SeqCst));
+/// }
+/// ```
+///
+/// Requires a target with atomic pointers.
+#[cfg(target_has_atomic = "ptr")]
+pub struct SharedGuard<T, F, S = Always>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    inner: Arc<Shared<T, F, S>>,
+}
+
+#[cfg(target_has_atomic = "ptr")]
+struct Shared<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    guard: ScopeGuard<T, F, S>,
+    // Cleared by `defuse`.
+    armed: AtomicBool,
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl<T, F, S> Drop for Shared<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn drop(&mut self) {
+        if !*self.armed.get_mut() {
+            ScopeGuard::disarm(&mut self.guard);
+        }
+    }
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl<T, F> SharedGuard<T, F, Always>
+where
+    F: FnOnce(T),
+{
+    /// Create a shared guard that owns `v` and calls `dropfn` when the last
+    /// owner is dropped.
+    pub fn new(v: T, dropfn: F) -> Self {
+        SharedGuard::with_strategy(v, dropfn)
+    }
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl<T, F, S> SharedGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    /// Create a shared guard that owns `v` and calls `dropfn` when the last
+    /// owner is dropped, if the strategy `S` allows it.
+    pub fn with_strategy(v: T, dropfn: F) -> Self
+    where
+        S: Default,
+    {
+        SharedGuard {
+            inner: Arc::new(Shared {
+                guard: ScopeGuard::with_strategy(v, dropfn),
+                armed: AtomicBool::new(true),
+            }),
+        }
+    }
+
+    /// Defuse the guard for all owners: the closure will not run, and the
+    /// value is dropped when the last owner is dropped.
+    pub fn defuse(guard: &Self) {
+        guard.inner.armed.store(false, Ordering::SeqCst);
+    }
+
+    /// Return `true` if the guard was defused by one of its owners.
+    pub fn is_defused(guard: &Self) -> bool {
+        !guard.inner.armed.load(Ordering::SeqCst)
+    }
+
+    /// Return the number of owners of the guard.
+    pub fn owners(guard: &Self) -> usize {
+        Arc::strong_count(&guard.inner)
+    }
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl<T, F, S> Clone for SharedGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn clone(&self) -> Self {
+        SharedGuard {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl<T, F, S> Deref for SharedGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.guard
+    }
+}
+
+#[cfg(target_has_atomic = "ptr")]
+impl<T, F, S> fmt::Debug for SharedGuard<T, F, S>
+where
+    T: fmt::Debug,
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(SharedGuard))
+            .field("value", &**self)
+            .field("owners", &SharedGuard::owners(self))
+            .field("defused", &SharedGuard::is_defused(self))
+            .finish()
+    }
+}
+
+/// A scope guard shared through an `Rc`: cloning it adds an owner, and the
+/// closure runs once, when the last owner is dropped.
+///
+/// This is the single-threaded version of
+/// [`SharedGuard`](struct.SharedGuard.html), for values and closures that are
+/// not `Send`, such as state shared by several callbacks of an event loop.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::RefCell;
+/// use scopeguard::shared::LocalSharedGuard;
+///
+/// fn main() {
+///     let log = RefCell::new(Vec::new());
+///     let session = LocalSharedGuard::new("session", |name| {
+///         log.borrow_mut().push(format!("{} closed", name));
+///     });
+///     let on_click = {
+///         let session = session.clone();
+///         let log = &log;
+///         move || log.borrow_mut().push(format!("click in {}", *session))
+///     };
+///     drop(session);
+///     on_click();
+///     drop(on_click);
+///     assert_eq!(*log.borrow(), ["click in session", "session closed"]);
+/// }
+/// ```
+pub struct LocalSharedGuard<T, F, S = Always>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    inner: Rc<LocalShared<T, F, S>>,
+}
+
+struct LocalShared<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    guard: ScopeGuard<T, F, S>,
+    // Cleared by `defuse`.
+    armed: Cell<bool>,
+}
+
+impl<T, F, S> Drop for LocalShared<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn drop(&mut self) {
+        if !self.armed.get() {
+            ScopeGuard::disarm(&mut self.guard);
+        }
+    }
+}
+
+impl<T, F> LocalSharedGuard<T, F, Always>
+where
+    F: FnOnce(T),
+{
+    /// Create a shared guard that owns `v` and calls `dropfn` when the last
+    /// owner is dropped.
+    pub fn new(v: T, dropfn: F) -> Self {
+        LocalSharedGuard::with_strategy(v, dropfn)
+    }
+}
+
+impl<T, F, S> LocalSharedGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    /// Create a shared guard that owns `v` and calls `dropfn` when the last
+    /// owner is dropped, if the strategy `S` allows it.
+    pub fn with_strategy(v: T, dropfn: F) -> Self
+    where
+        S: Default,
+    {
+        LocalSharedGuard {
+            inner: Rc::new(LocalShared {
+                guard: ScopeGuard::with_strategy(v, dropfn),
+                armed: Cell::new(true),
+            }),
+        }
+    }
+
+    /// Defuse the guard for all owners: the closure will not run, and the
+    /// value is dropped when the last owner is dropped.
+    pub fn defuse(guard: &Self) {
+        guard.inner.armed.set(false);
+    }
+
+    /// Return `true` if the guard was defused by one of its owners.
+    pub fn is_defused(guard: &Self) -> bool {
+        !guard.inner.armed.get()
+    }
+
+    /// Return the number of owners of the guard.
+    pub fn owners(guard: &Self) -> usize {
+        Rc::strong_count(&guard.inner)
+    }
+}
+
+impl<T, F, S> Clone for LocalSharedGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn clone(&self) -> Self {
+        LocalSharedGuard {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T, F, S> Deref for LocalSharedGuard<T, F, S>
+where
+    F: FnOnce(T),
+    S: Strategy,
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner.guard
+    }
+}
+
+impl<T, F, S> fmt::Debug for LocalSharedGuard<T, F, S>
+where
+    T: fmt::Debug,
+    F: FnOnce(T),
+    S: Strategy,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(LocalSharedGuard))
+            .field("value", &**self)
+            .field("owners", &LocalSharedGuard::owners(self))
+            .field("defused", &LocalSharedGuard::is_defused(self))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[cfg(target_has_atomic = "ptr")]
+    #[test]
+    fn test_shared_guard() {
+        let runs = Cell::new(0);
+        let g = SharedGuard::new(1, |n| runs.set(runs.get() + n));
+        let g2 = g.clone();
+        assert_eq!(SharedGuard::owners(&g), 2);
+        drop(g);
+        assert_eq!(runs.get(), 0);
+        drop(g2);
+        assert_eq!(runs.get(), 1);
+
+        let g = SharedGuard::new(10, |n| runs.set(runs.get() + n));
+        let g2 = g.clone();
+        SharedGuard::defuse(&g2);
+        drop(g2);
+        assert!(SharedGuard::is_defused(&g));
+        drop(g);
+        assert_eq!(runs.get(), 1);
+    }
+
+    #[test]
+    fn test_local_shared_guard() {
+        let runs = Cell::new(0);
+        let g = LocalSharedGuard::new(1, |n| runs.set(runs.get() + n));
+        let g2 = g.clone();
+        assert_eq!(LocalSharedGuard::owners(&g2), 2);
+        drop(g2);
+        assert_eq!(runs.get(), 0);
+        drop(g);
+        assert_eq!(runs.get(), 1);
+
+        let g = LocalSharedGuard::new(10, |n| runs.set(runs.get() + n));
+        LocalSharedGuard::defuse(&g.clone());
+        drop(g);
+        assert_eq!(runs.get(), 1);
+    }
+}
@@ -0,0 +1,373 @@
+//! Guards whose cleanup is an asynchronous task.
+//!
+//! When such a guard is dropped, its closure creates a future that is spawned
+//! on an executor, instead of running the cleanup synchronously in the
+//! destructor. The guard does not wait for the task to finish.
+//!
+//! Requires one of the crate features `tokio`, `async-std` or `smol`, which
+//! enable the corresponding [`Spawn`](trait.Spawn.html) implementation, or
+//! `wasm`, which enables [`guard_spawn_local`](fn.guard_spawn_local.html) on
+//! `wasm32` targets.
+
+use std::future::Future;
+#[cfg(feature = "tokio")]
+use std::time::Duration;
+#[cfg(feature = "tokio")]
+use tokio::runtime::Runtime;
+#[cfg(feature = "tokio")]
+use tokio::task::{AbortHandle, JoinHandle};
+
+use guard;
+use ScopeGuard;
+
+/// An executor that can spawn cleanup tasks.
+pub trait Spawn {
+    /// Spawn `task` to run in the background.
+    fn spawn<Fut>(&self, task: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static;
+}
+
+impl<S: Spawn + ?Sized> Spawn for &S {
+    fn spawn<Fut>(&self, task: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        (**self).spawn(task)
+    }
+}
+
+/// Spawns tasks with `tokio::spawn`.
+///
+/// The guard must be dropped within a Tokio runtime.
+///
+/// Requires crate feature `tokio`.
+#[cfg(feature = "tokio")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Tokio;
+
+#[cfg(feature = "tokio")]
+impl Spawn for Tokio {
+    fn spawn<Fut>(&self, task: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        drop(::tokio::spawn(task));
+    }
+}
+
+/// A set of Tokio tasks that are aborted when the `TaskScope` is dropped.
+///
+/// Tasks spawned through the scope, or registered with it, are aborted at
+/// scope exit or during unwinding, unless they have completed already. The
+/// scope does not wait for them: abortion takes effect the next time each
+/// task yields.
+///
+/// Requires crate feature `tokio`.
+///
+/// ```
+/// extern crate scopeguard;
+/// extern crate tokio;
+///
+/// use scopeguard::spawn::TaskScope;
+///
+/// fn main() {
+///     let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+///     let _enter = runtime.enter();
+///     let handle = {
+///         let mut scope = TaskScope::new();
+///         scope.spawn(std::future::pending::<()>())
+///     };
+///     assert!(runtime.block_on(handle).unwrap_err().is_cancelled());
+/// }
+/// ```
+#[cfg(feature = "tokio")]
+#[derive(Debug)]
+pub struct TaskScope {
+    handles: ScopeGuard<Vec<AbortHandle>, fn(Vec<AbortHandle>)>,
+}
+
+#[cfg(feature = "tokio")]
+impl TaskScope {
+    /// Create an empty `TaskScope`.
+    pub fn new() -> Self {
+        fn abort_all(handles: Vec<AbortHandle>) {
+            for handle in handles {
+                handle.abort();
+            }
+        }
+        TaskScope {
+            handles: guard(Vec::new(), abort_all),
+        }
+    }
+
+    /// Spawn `task` with `tokio::spawn` and register it with the scope.
+    ///
+    /// # Panics
+    ///
+    /// Panics if called outside of a Tokio runtime.
+    pub fn spawn<Fut>(&mut self, task: Fut) -> JoinHandle<Fut::Output>
+    where
+        Fut: Future + Send + 'static,
+        Fut::Output: Send + 'static,
+    {
+        let handle = ::tokio::spawn(task);
+        self.register(&handle);
+        handle
+    }
+
+    /// Register an already spawned task with the scope.
+    pub fn register<T>(&mut self, handle: &JoinHandle<T>) {
+        self.handles.retain(|handle| !handle.is_finished());
+        self.handles.push(handle.abort_handle());
+    }
+
+    /// Return the number of registered tasks that have not finished.
+    pub fn len(&self) -> usize {
+        self.handles
+            .iter()
+            .filter(|handle| !handle.is_finished())
+            .count()
+    }
+
+    /// Return `true` if all registered tasks have finished.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Release the registered tasks, so that they are not aborted.
+    pub fn detach(self) {
+        ScopeGuard::into_inner(self.handles);
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Default for TaskScope {
+    fn default() -> Self {
+        TaskScope::new()
+    }
+}
+
+/// Create a new `ScopeGuard` owning `runtime`, which shuts it down with
+/// `Runtime::shutdown_timeout(timeout)` when it is dropped.
+///
+/// Dropping a `Runtime` waits for all of its blocking tasks without a time
+/// limit; the guard bounds the wait, so that tests and binaries with an
+/// embedded runtime don't hang when a scope unwinds.
+///
+/// Requires crate feature `tokio`.
+///
+/// ```
+/// extern crate scopeguard;
+/// extern crate tokio;
+///
+/// use std::time::Duration;
+///
+/// fn main() {
+///     let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+///     let runtime = scopeguard::spawn::guard_runtime_shutdown(runtime, Duration::from_secs(1));
+///     runtime.spawn_blocking(|| std::thread::sleep(Duration::from_secs(60)));
+///     // returns after about one second
+/// }
+/// ```
+#[cfg(feature = "tokio")]
+pub fn guard_runtime_shutdown(
+    runtime: Runtime,
+    timeout: Duration,
+) -> ScopeGuard<Runtime, impl FnOnce(Runtime)> {
+    guard(runtime, move |runtime| runtime.shutdown_timeout(timeout))
+}
+
+/// Spawns tasks with `async_std::task::spawn`.
+///
+/// Requires crate feature `async-std`.
+#[cfg(feature = "async-std")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct AsyncStd;
+
+#[cfg(feature = "async-std")]
+impl Spawn for AsyncStd {
+    fn spawn<Fut>(&self, task: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        drop(::async_std::task::spawn(task));
+    }
+}
+
+/// Spawns tasks on smol's global executor, with `smol::spawn`.
+///
+/// Requires crate feature `smol`.
+#[cfg(feature = "smol")]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Smol;
+
+#[cfg(feature = "smol")]
+impl Spawn for Smol {
+    fn spawn<Fut>(&self, task: Fut)
+    where
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        ::smol::spawn(task).detach();
+    }
+}
+
+/// Create a new `ScopeGuard` owning `v`, which spawns the future returned by
+/// `cleanup` on `spawner` when it is dropped.
+///
+/// `cleanup` itself runs synchronously in the destructor, so it should only
+/// create the future; the work belongs in the future.
+pub fn guard_spawn<T, S, F, Fut>(v: T, spawner: S, cleanup: F) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    S: Spawn,
+    F: FnOnce(T) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    guard(v, move |v| spawner.spawn(cleanup(v)))
+}
+
+/// Create a new `ScopeGuard` owning `v`, which schedules the future returned
+/// by `cleanup` on the browser's microtask queue when it is dropped, with
+/// `wasm_bindgen_futures::spawn_local`.
+///
+/// This is for cleanup that must run asynchronously, for example because it
+/// calls back into JavaScript. The future does not need to be `Send`.
+///
+/// Requires crate feature `wasm`, and the `wasm32` target architecture.
+///
+/// ```ignore
+/// let _release = scopeguard::spawn::guard_spawn_local(handle, |handle| async move {
+///     handle.release().await;
+/// });
+/// ```
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+pub fn guard_spawn_local<T, F, Fut>(v: T, cleanup: F) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    F: FnOnce(T) -> Fut,
+    Fut: Future<Output = ()> + 'static,
+{
+    guard(v, move |v| ::wasm_bindgen_futures::spawn_local(cleanup(v)))
+}
+
+/// Macro to create a `ScopeGuard` that spawns a future when the scope is
+/// exited.
+///
+/// The macro takes a spawner (see [`spawn::Spawn`](spawn/trait.Spawn.html))
+/// and an expression for the future. The expression is evaluated when the
+/// scope is exited.
+///
+/// ```ignore
+/// defer_async!(scopeguard::spawn::Smol, async move {
+///     connection.close().await;
+/// });
+/// ```
+///
+/// Requires one of the crate features `tokio`, `async-std` or `smol`, or the
+/// crate feature `wasm` on `wasm32` targets.
+#[cfg(any(
+    feature = "tokio",
+    feature = "async-std",
+    feature = "smol",
+    all(feature = "wasm", target_arch = "wasm32")
+))]
+#[macro_export]
+macro_rules! defer_async {
+    ($spawner:expr, $task:expr) => {
+        let _guard = $crate::spawn::guard_spawn((), $spawner, |()| $task);
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+    use std::sync::mpsc::{channel, Sender};
+    use std::task::Poll;
+    #[cfg(all(any(feature = "async-std", feature = "smol"), not(feature = "tokio")))]
+    use std::time::Duration;
+
+    fn send_task(tx: Sender<u32>, value: u32) -> impl Future<Output = ()> + Send {
+        let mut tx = Some(tx);
+        poll_fn(move |_| {
+            tx.take().unwrap().send(value).unwrap();
+            Poll::Ready(())
+        })
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_spawn_tokio() {
+        let runtime = ::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let (tx, rx) = channel();
+        {
+            let _enter = runtime.enter();
+            let _guard = guard_spawn(1, Tokio, move |v| send_task(tx, v));
+        }
+        runtime.block_on(::tokio::task::yield_now());
+        assert_eq!(rx.try_recv(), Ok(1));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_task_scope_aborts_on_unwind() {
+        use std::future::pending;
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let runtime = ::tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let _enter = runtime.enter();
+        let finished = ::tokio::spawn(std::future::ready(()));
+        runtime.block_on(::tokio::task::yield_now());
+        let mut handles = Vec::new();
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let mut scope = TaskScope::new();
+            scope.register(&finished);
+            handles.push(scope.spawn(pending::<()>()));
+            assert_eq!(scope.len(), 1);
+            panic!("failure")
+        }));
+        for handle in handles {
+            assert!(runtime.block_on(handle).unwrap_err().is_cancelled());
+        }
+        assert!(runtime.block_on(finished).is_ok());
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn test_guard_runtime_shutdown_bounds_wait() {
+        use std::time::Instant;
+
+        let start = Instant::now();
+        {
+            let runtime = ::tokio::runtime::Builder::new_current_thread()
+                .build()
+                .unwrap();
+            let runtime = guard_runtime_shutdown(runtime, Duration::from_millis(10));
+            runtime.spawn_blocking(|| std::thread::sleep(Duration::from_secs(5)));
+        }
+        assert!(start.elapsed() < Duration::from_secs(4));
+    }
+
+    #[cfg(feature = "async-std")]
+    #[test]
+    fn test_spawn_async_std() {
+        let (tx, rx) = channel();
+        {
+            defer_async!(AsyncStd, send_task(tx, 2));
+        }
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(2));
+    }
+
+    #[cfg(feature = "smol")]
+    #[test]
+    fn test_spawn_smol() {
+        let (tx, rx) = channel();
+        {
+            let _guard = guard_spawn(3, Smol, move |v| send_task(tx, v));
+        }
+        assert_eq!(rx.recv_timeout(Duration::from_secs(10)), Ok(3));
+    }
+}
@@ -0,0 +1,93 @@
+//! Guards for Tokio task-local values.
+//!
+//! Requires crate feature `tokio`.
+
+use std::cell::RefCell;
+use tokio::task::LocalKey;
+use ScopeGuard;
+
+/// Set the task-local `key` to `value`, and restore its previous value when
+/// the returned guard goes out of scope.
+///
+/// The guard holds the previous value, which is accessible through deref.
+///
+/// # Panics
+///
+/// Panics if called outside of a `key.scope(..)` or `key.sync_scope(..)`,
+/// or if the value is currently borrowed.
+///
+/// ```
+/// #[macro_use]
+/// extern crate tokio;
+/// extern crate scopeguard;
+///
+/// use std::cell::RefCell;
+///
+/// task_local! {
+///     static LEVEL: RefCell<u32>;
+/// }
+///
+/// fn main() {
+///     LEVEL.sync_scope(RefCell::new(0), || {
+///         {
+///             let previous = scopeguard::task_local::set(&LEVEL, 1);
+///             assert_eq!(*previous, 0);
+///             assert_eq!(LEVEL.with(|l| *l.borrow()), 1);
+///         }
+///         assert_eq!(LEVEL.with(|l| *l.borrow()), 0);
+///     });
+/// }
+/// ```
+pub fn set<T>(key: &'static LocalKey<RefCell<T>>, value: T) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    T: 'static,
+{
+    let previous = key.with(|cell| cell.replace(value));
+    ::guard(previous, move |previous| {
+        // The scope of the task-local may already have ended.
+        let _ = key.try_with(|cell| *cell.borrow_mut() = previous);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::poll_fn;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use std::task::Poll;
+
+    tokio::task_local! {
+        static VALUE: RefCell<&'static str>;
+    }
+
+    fn get() -> &'static str {
+        VALUE.with(|v| *v.borrow())
+    }
+
+    #[test]
+    fn test_set_restores_on_unwind() {
+        VALUE.sync_scope(RefCell::new("outer"), || {
+            let _ = catch_unwind(AssertUnwindSafe(|| {
+                let _guard = set(&VALUE, "inner");
+                assert_eq!(get(), "inner");
+                panic!("failure")
+            }));
+            assert_eq!(get(), "outer");
+        });
+    }
+
+    #[test]
+    fn test_set_in_task() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let task = poll_fn(|_| {
+            let guard = set(&VALUE, "inner");
+            assert_eq!(get(), "inner");
+            drop(guard);
+            assert_eq!(get(), "outer");
+            Poll::Ready(())
+        });
+        runtime.block_on(VALUE.scope(RefCell::new("outer"), task));
+    }
+}
@@ -0,0 +1,736 @@
+//! Ordered teardown of named cleanups and owned values.
+//!
+//! Requires crate feature `alloc`.
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use std::any::TypeId;
+use std::cell::RefCell;
+use std::fmt;
+
+use guard;
+use ScopeGuard;
+
+/// A container of named cleanups that run when it is dropped, in an order
+/// that respects “run after” constraints between them.
+///
+/// Cleanups without constraints between them run in reverse order of
+/// registration, like a series of guards would. A constraint that names a
+/// cleanup which is not registered is ignored.
+///
+/// If a cleanup panics, the remaining cleanups still run during unwinding.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::RefCell;
+/// use scopeguard::teardown::Teardown;
+///
+/// fn main() {
+///     let log = RefCell::new(Vec::new());
+///     {
+///         let mut teardown = Teardown::new();
+///         teardown.add("disk", || log.borrow_mut().push("disk"));
+///         teardown.add_after("cache", &["network"], || log.borrow_mut().push("cache")).unwrap();
+///         teardown.add_after("disk", &["cache"], || log.borrow_mut().push("disk flush")).unwrap();
+///         teardown.add("network", || log.borrow_mut().push("network"));
+///     }
+///     assert_eq!(*log.borrow(), ["network", "cache", "disk flush", "disk"]);
+/// }
+/// ```
+pub struct Teardown<'a> {
+    entries: Vec<Entry<'a>>,
+    seed: Option<u64>,
+}
+
+struct Entry<'a> {
+    name: String,
+    after: Vec<String>,
+    cleanup: Box<dyn FnOnce() + 'a>,
+}
+
+/// The error returned when a constraint would make the cleanup order cyclic.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CycleError {
+    name: String,
+}
+
+impl CycleError {
+    /// Return the name of the cleanup whose constraints were rejected.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "cleanup order constraints of {:?} form a cycle",
+            self.name
+        )
+    }
+}
+
+#[cfg(feature = "use_std")]
+impl std::error::Error for CycleError {}
+
+impl<'a> Teardown<'a> {
+    /// Create an empty `Teardown`.
+    pub fn new() -> Self {
+        Teardown {
+            entries: Vec::new(),
+            seed: None,
+        }
+    }
+
+    /// Run cleanups without constraints between them in a pseudo-random
+    /// order determined by `seed`, instead of in reverse order of
+    /// registration.
+    ///
+    /// This is meant for tests, to find cleanups that depend on an order
+    /// which is not declared with [`add_after`](#method.add_after). The order
+    /// is the same for the same seed and registrations, so a failure can be
+    /// reproduced. If a cleanup panics, the seed is printed on stderr (with
+    /// crate feature `use_std`).
+    ///
+    /// ```
+    /// extern crate scopeguard;
+    ///
+    /// use scopeguard::teardown::Teardown;
+    ///
+    /// fn main() {
+    ///     let mut teardown = Teardown::new();
+    ///     teardown.shuffle(42);
+    ///     teardown.add("a", || {});
+    ///     teardown.add_after("b", &["a"], || {}).unwrap();
+    ///     teardown.add("c", || {});
+    /// }
+    /// ```
+    pub fn shuffle(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
+
+    /// Return the seed set with [`shuffle`](#method.shuffle), if any.
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Return the number of registered cleanups.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return `true` if no cleanups are registered.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Register `cleanup` under `name`, without constraints.
+    pub fn add<N, C>(&mut self, name: N, cleanup: C)
+    where
+        N: Into<String>,
+        C: FnOnce() + 'a,
+    {
+        self.entries.push(Entry {
+            name: name.into(),
+            after: Vec::new(),
+            cleanup: Box::new(cleanup),
+        });
+    }
+
+    /// Register `cleanup` under `name`, to run after all cleanups named in
+    /// `after`.
+    ///
+    /// Return an error, and do not register the cleanup, if the constraints
+    /// would form a cycle.
+    pub fn add_after<N, C>(&mut self, name: N, after: &[&str], cleanup: C) -> Result<(), CycleError>
+    where
+        N: Into<String>,
+        C: FnOnce() + 'a,
+    {
+        let name = name.into();
+        if after.iter().any(|dep| self.depends_on(dep, &name)) {
+            return Err(CycleError { name });
+        }
+        self.entries.push(Entry {
+            name,
+            after: after.iter().map(|&dep| dep.into()).collect(),
+            cleanup: Box::new(cleanup),
+        });
+        Ok(())
+    }
+
+    /// Run all cleanups now.
+    pub fn run(self) {
+        drop(self)
+    }
+
+    /// Return `true` if `name` is, or must run after, `target`.
+    fn depends_on(&self, name: &str, target: &str) -> bool {
+        let mut stack = alloc::vec![name];
+        let mut visited = Vec::new();
+        while let Some(name) = stack.pop() {
+            if name == target {
+                return true;
+            }
+            if visited.contains(&name) {
+                continue;
+            }
+            visited.push(name);
+            for entry in self.entries.iter().filter(|e| e.name == name) {
+                stack.extend(entry.after.iter().map(|dep| &dep[..]));
+            }
+        }
+        false
+    }
+
+    /// Remove and return the cleanups in execution order.
+    fn take_ordered(&mut self) -> Vec<Box<dyn FnOnce() + 'a>> {
+        let entries = &self.entries;
+        let mut rng = self.seed.map(XorShift::new);
+        let mut done = alloc::vec![false; entries.len()];
+        let mut order = Vec::with_capacity(entries.len());
+        while order.len() < entries.len() {
+            // The entries whose constraints are satisfied, latest registered
+            // first. There is always one, since the constraints are acyclic.
+            let mut ready = (0..entries.len()).rev().filter(|&i| {
+                !done[i]
+                    && entries[i].after.iter().all(|dep| {
+                        entries
+                            .iter()
+                            .enumerate()
+                            .all(|(j, e)| j == i || done[j] || e.name != *dep)
+                    })
+            });
+            let next = match rng {
+                Some(ref mut rng) => {
+                    let ready: Vec<_> = ready.collect();
+                    ready.get(rng.next() as usize % ready.len().max(1)).cloned()
+                }
+                None => ready.next(),
+            }
+            .expect("cleanup constraints are acyclic");
+            done[next] = true;
+            order.push(next);
+        }
+        let mut cleanups: Vec<_> = self.entries.drain(..).map(|e| Some(e.cleanup)).collect();
+        order
+            .into_iter()
+            .map(|i| cleanups[i].take().unwrap())
+            .collect()
+    }
+}
+
+impl<'a> Default for Teardown<'a> {
+    fn default() -> Self {
+        Teardown::new()
+    }
+}
+
+impl<'a> Drop for Teardown<'a> {
+    fn drop(&mut self) {
+        let mut cleanups = self.take_ordered();
+        cleanups.reverse();
+        #[cfg(feature = "use_std")]
+        let _report = self.seed.map(|seed| {
+            ::guard_on_unwind(seed, |seed| {
+                eprintln!("scopeguard: teardown in shuffled order with seed {}", seed)
+            })
+        });
+        run_all(cleanups);
+    }
+}
+
+/// A small pseudo-random number generator (xorshift64*).
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // The state must not be zero.
+        XorShift(seed ^ 0x9E37_79B9_7F4A_7C15)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[cfg(feature = "use_std")]
+type StaticGuards = std::sync::Mutex<Vec<Box<dyn FnOnce() + Send>>>;
+
+#[cfg(feature = "use_std")]
+fn static_guards() -> &'static StaticGuards {
+    use std::ptr;
+    use std::sync::atomic::{AtomicPtr, Ordering};
+    use std::sync::Once;
+
+    static INIT: Once = Once::new();
+    static GUARDS: AtomicPtr<StaticGuards> = AtomicPtr::new(ptr::null_mut());
+    INIT.call_once(|| {
+        let guards = Box::new(StaticGuards::default());
+        GUARDS.store(Box::into_raw(guards), Ordering::Release);
+    });
+    // SAFETY: The pointer was set from a leaked box by `call_once`, which
+    // has completed, and is never freed.
+    unsafe { &*GUARDS.load(Ordering::Acquire) }
+}
+
+/// Register `cleanup` in the global list drained by
+/// [`run_static_guards`](fn.run_static_guards.html).
+///
+/// This lets a library hook orderly teardown without owning a scope in
+/// `main`. Nothing runs the cleanups automatically: if the program never
+/// calls `run_static_guards`, they don't run.
+///
+/// Requires crate feature `use_std`.
+#[cfg(feature = "use_std")]
+pub fn register_static_guard<C>(cleanup: C)
+where
+    C: FnOnce() + Send + 'static,
+{
+    lock_static_guards().push(Box::new(cleanup));
+}
+
+/// Run all cleanups registered with
+/// [`register_static_guard`](fn.register_static_guard.html), in reverse order
+/// of registration.
+///
+/// Call this at the end of `main`. Cleanups registered while this runs also
+/// run before it returns. If a cleanup panics, the other cleanups registered
+/// before this call still run during unwinding.
+///
+/// Requires crate feature `use_std`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::teardown::{register_static_guard, run_static_guards};
+///
+/// fn init_logging() {
+///     register_static_guard(|| println!("flushing logs"));
+/// }
+///
+/// fn main() {
+///     init_logging();
+///     // ...
+///     run_static_guards();
+/// }
+/// ```
+#[cfg(feature = "use_std")]
+pub fn run_static_guards() {
+    loop {
+        let cleanups = std::mem::take(&mut *lock_static_guards());
+        if cleanups.is_empty() {
+            break;
+        }
+        run_all(
+            cleanups
+                .into_iter()
+                .map(|cleanup| cleanup as Box<dyn FnOnce()>)
+                .collect(),
+        );
+    }
+}
+
+#[cfg(feature = "use_std")]
+fn lock_static_guards() -> std::sync::MutexGuard<'static, Vec<Box<dyn FnOnce() + Send>>> {
+    // Cleanups never run while the lock is held, so it can't be poisoned by
+    // them; ignore poisoning anyway rather than lose the list.
+    static_guards()
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Run the cleanups from last to first; if one panics, the rest still run.
+pub(crate) fn run_all<'a>(mut cleanups: Vec<Box<dyn FnOnce() + 'a>>) {
+    while let Some(cleanup) = cleanups.pop() {
+        let rest = guard(cleanups, run_all);
+        cleanup();
+        cleanups = ScopeGuard::into_inner(rest);
+    }
+}
+
+impl<'a> fmt::Debug for Teardown<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|e| (&e.name, &e.after)))
+            .finish()
+    }
+}
+
+/// A scope that owns values of any type, each with a finalizer, and finalizes
+/// them in reverse order of insertion when it is dropped.
+///
+/// Moving a value into the scope returns a reference to it that is valid for
+/// as long as the scope is borrowed, so several values can be set up through
+/// a shared reference and used side by side. When the scope is dropped, each
+/// finalizer receives its value by value.
+///
+/// If a finalizer panics, the remaining finalizers still run during
+/// unwinding.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::RefCell;
+/// use scopeguard::teardown::OwnedScope;
+///
+/// fn main() {
+///     let log = RefCell::new(Vec::new());
+///     {
+///         let scope = OwnedScope::new();
+///         let name = scope.add(String::from("conn"), |s| log.borrow_mut().push(s));
+///         let buffer = scope.add(Vec::new(), |b: Vec<u8>| {
+///             log.borrow_mut().push(format!("flush {}", b.len()))
+///         });
+///         name.push_str("-1");
+///         buffer.push(1);
+///     }
+///     assert_eq!(*log.borrow(), ["flush 1", "conn-1"]);
+/// }
+/// ```
+pub struct OwnedScope<'a> {
+    finalizers: RefCell<Vec<Box<dyn FnOnce() + 'a>>>,
+}
+
+impl<'a> OwnedScope<'a> {
+    /// Create an empty `OwnedScope`.
+    pub fn new() -> Self {
+        OwnedScope {
+            finalizers: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Return the number of values in the scope.
+    pub fn len(&self) -> usize {
+        self.finalizers.borrow().len()
+    }
+
+    /// Return `true` if the scope holds no values.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Move `value` into the scope, to be passed to `finalizer` when the
+    /// scope is dropped, and return a reference to it.
+    #[allow(clippy::mut_from_ref)]
+    pub fn add<T, F>(&self, value: T, finalizer: F) -> &mut T
+    where
+        T: 'a,
+        F: FnOnce(T) + 'a,
+    {
+        let ptr = Box::into_raw(Box::new(value));
+        self.finalizers.borrow_mut().push(Box::new(move || {
+            // SAFETY: `ptr` comes from `Box::into_raw`, and this closure is
+            // the only place that frees it. It runs at most once, when the
+            // scope is dropped, after all references returned by `add` have
+            // expired.
+            let value = unsafe { *Box::from_raw(ptr) };
+            finalizer(value)
+        }));
+        // SAFETY: The allocation is distinct for every call and lives until
+        // the scope is dropped, which the borrow of `self` rules out for the
+        // lifetime of the returned reference.
+        unsafe { &mut *ptr }
+    }
+
+    /// Move `value` into the scope, to be dropped when the scope is dropped,
+    /// and return a reference to it.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc<T>(&self, value: T) -> &mut T
+    where
+        T: 'a,
+    {
+        self.add(value, drop)
+    }
+}
+
+impl<'a> Default for OwnedScope<'a> {
+    fn default() -> Self {
+        OwnedScope::new()
+    }
+}
+
+impl<'a> Drop for OwnedScope<'a> {
+    fn drop(&mut self) {
+        let finalizers = std::mem::take(self.finalizers.get_mut());
+        run_all(finalizers);
+    }
+}
+
+impl<'a> fmt::Debug for OwnedScope<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(OwnedScope))
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// The key of a cleanup in a [`Registry`](struct.Registry.html).
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Key {
+    /// A key for the subsystem implemented by a type.
+    Type(TypeId),
+    /// A key for a subsystem identified by name.
+    Name(String),
+}
+
+impl Key {
+    /// Return the key for type `T`.
+    pub fn of<T: ?Sized + 'static>() -> Self {
+        Key::Type(TypeId::of::<T>())
+    }
+}
+
+impl<'s> From<&'s str> for Key {
+    fn from(name: &'s str) -> Self {
+        Key::Name(name.into())
+    }
+}
+
+impl From<String> for Key {
+    fn from(name: String) -> Self {
+        Key::Name(name)
+    }
+}
+
+impl From<TypeId> for Key {
+    fn from(id: TypeId) -> Self {
+        Key::Type(id)
+    }
+}
+
+/// A registry of cleanups under unique keys, which run in reverse order of
+/// registration when it is dropped.
+///
+/// Unlike guards, registered cleanups can be inspected, replaced and removed,
+/// which suits hosts that load and unload plugins: each plugin registers its
+/// teardown under its type or name.
+///
+/// If a cleanup panics, the remaining cleanups still run during unwinding.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::cell::RefCell;
+/// use scopeguard::teardown::{Key, Registry};
+///
+/// struct Audio;
+///
+/// fn main() {
+///     let log = RefCell::new(Vec::new());
+///     {
+///         let mut registry = Registry::new();
+///         registry.insert(Key::of::<Audio>(), || log.borrow_mut().push("audio"));
+///         registry.insert("net", || log.borrow_mut().push("net v1"));
+///         // Reloading the plugin replaces its teardown, keeping its position.
+///         registry.insert("net", || log.borrow_mut().push("net v2"));
+///         assert_eq!(registry.len(), 2);
+///     }
+///     assert_eq!(*log.borrow(), ["net v2", "audio"]);
+/// }
+/// ```
+pub struct Registry<'a> {
+    entries: Vec<(Key, Box<dyn FnOnce() + 'a>)>,
+}
+
+impl<'a> Registry<'a> {
+    /// Create an empty `Registry`.
+    pub fn new() -> Self {
+        Registry {
+            entries: Vec::new(),
+        }
+    }
+
+    /// Return the number of registered cleanups.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Return `true` if no cleanups are registered.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Return `true` if a cleanup is registered under `key`.
+    pub fn contains(&self, key: &Key) -> bool {
+        self.position(key).is_some()
+    }
+
+    /// Return an iterator over the keys, in registration order.
+    pub fn keys(&self) -> impl Iterator<Item = &Key> {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    /// Register `cleanup` under `key`.
+    ///
+    /// If a cleanup is already registered under `key`, it is replaced, keeping
+    /// its place in the order, and returned without running it.
+    pub fn insert<K, C>(&mut self, key: K, cleanup: C) -> Option<Box<dyn FnOnce() + 'a>>
+    where
+        K: Into<Key>,
+        C: FnOnce() + 'a,
+    {
+        let key = key.into();
+        let cleanup: Box<dyn FnOnce() + 'a> = Box::new(cleanup);
+        match self.position(&key) {
+            Some(i) => Some(std::mem::replace(&mut self.entries[i].1, cleanup)),
+            None => {
+                self.entries.push((key, cleanup));
+                None
+            }
+        }
+    }
+
+    /// Unregister the cleanup under `key` and return it without running it.
+    pub fn remove(&mut self, key: &Key) -> Option<Box<dyn FnOnce() + 'a>> {
+        self.position(key).map(|i| self.entries.remove(i).1)
+    }
+
+    /// Run all cleanups now.
+    pub fn run(self) {
+        drop(self)
+    }
+
+    fn position(&self, key: &Key) -> Option<usize> {
+        self.entries.iter().position(|(k, _)| k == key)
+    }
+}
+
+impl<'a> Default for Registry<'a> {
+    fn default() -> Self {
+        Registry::new()
+    }
+}
+
+impl<'a> Drop for Registry<'a> {
+    fn drop(&mut self) {
+        run_all(self.entries.drain(..).map(|(_, cleanup)| cleanup).collect());
+    }
+}
+
+impl<'a> fmt::Debug for Registry<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.keys()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    #[test]
+    fn test_cycle_detection() {
+        let mut teardown = Teardown::new();
+        teardown.add_after("a", &["b"], || {}).unwrap();
+        teardown.add_after("b", &["c"], || {}).unwrap();
+        assert_eq!(
+            teardown.add_after("c", &["a"], || {}).unwrap_err().name(),
+            "c"
+        );
+        assert_eq!(
+            teardown.add_after("d", &["d"], || {}).unwrap_err().name(),
+            "d"
+        );
+        assert_eq!(teardown.len(), 2);
+    }
+
+    #[test]
+    fn test_owned_scope() {
+        let log = RefCell::new(Vec::new());
+        {
+            let scope = OwnedScope::new();
+            let a = scope.add(1, |n| log.borrow_mut().push(n));
+            let b = scope.add(2, |n| log.borrow_mut().push(n));
+            *a += 10;
+            *b += 20;
+            scope.alloc(String::from("dropped"));
+            assert_eq!(scope.len(), 3);
+        }
+        assert_eq!(*log.borrow(), [22, 11]);
+    }
+
+    #[test]
+    fn test_registry_replace_remove() {
+        let log = RefCell::new(Vec::new());
+        let mut registry = Registry::new();
+        registry.insert("a", || log.borrow_mut().push("a"));
+        registry.insert(Key::of::<u8>(), || log.borrow_mut().push("u8"));
+        registry.insert("c", || log.borrow_mut().push("c"));
+        registry
+            .insert("a", || log.borrow_mut().push("a2"))
+            .unwrap()();
+        let removed = registry.remove(&Key::of::<u8>());
+        assert!(removed.is_some());
+        assert!(!registry.contains(&Key::of::<u8>()));
+        assert!(registry.remove(&"missing".into()).is_none());
+        registry.run();
+        assert_eq!(*log.borrow(), ["a", "c", "a2"]);
+    }
+
+    #[cfg(feature = "use_std")]
+    #[test]
+    fn test_static_guards() {
+        use std::sync::{Arc, Mutex};
+
+        let log = Arc::new(Mutex::new(Vec::new()));
+        for i in 0..2 {
+            let log = log.clone();
+            register_static_guard(move || {
+                let nested = log.clone();
+                log.lock().unwrap().push(i);
+                if i == 0 {
+                    register_static_guard(move || nested.lock().unwrap().push(2));
+                }
+            });
+        }
+        run_static_guards();
+        assert_eq!(*log.lock().unwrap(), [1, 0, 2]);
+    }
+
+    #[test]
+    fn test_shuffle_respects_constraints() {
+        let orders: Vec<_> = (0..20)
+            .map(|seed| {
+                let log = RefCell::new(Vec::new());
+                {
+                    let mut teardown = Teardown::new();
+                    teardown.shuffle(seed);
+                    for &name in &["a", "b", "c", "d"] {
+                        let log = &log;
+                        teardown.add(name, move || log.borrow_mut().push(name));
+                    }
+                    let log = &log;
+                    teardown
+                        .add_after("e", &["a"], move || log.borrow_mut().push("e"))
+                        .unwrap();
+                }
+                let order = log.into_inner();
+                let pos = |name| order.iter().position(|&n| n == name).unwrap();
+                assert!(pos("a") < pos("e"), "seed {}: {:?}", seed, order);
+                order
+            })
+            .collect();
+        assert!(orders.iter().any(|order| *order != orders[0]));
+    }
+
+    #[test]
+    fn test_lifo_without_constraints() {
+        let log = RefCell::new(Vec::new());
+        let mut teardown = Teardown::new();
+        for i in 0..3 {
+            let log = &log;
+            teardown.add("x", move || log.borrow_mut().push(i));
+        }
+        teardown.run();
+        assert_eq!(*log.borrow(), [2, 1, 0]);
+    }
+}
@@ -0,0 +1,159 @@
+//! Utilities for testing cleanup code.
+//!
+//! Requires crate feature `use_std`.
+
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use guard;
+use ScopeGuard;
+
+/// A log that records the order in which cleanups run.
+///
+/// Wrap cleanups with [`record`](#method.record) or create guards with
+/// [`guard`](#method.guard); each records its name in the log when it starts
+/// running. The log is cheap to clone and all clones share the same
+/// entries, so it can be moved into closures and across threads.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use scopeguard::testing::TeardownLog;
+///
+/// fn main() {
+///     let log = TeardownLog::new();
+///     {
+///         let _buffers = log.guard("free_buffers", vec![0u8; 16], drop);
+///         let _socket = log.guard("close_socket", (), |_| {});
+///     }
+///     log.assert_ran_before("close_socket", "free_buffers");
+///     assert_eq!(log.entries(), ["close_socket", "free_buffers"]);
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct TeardownLog {
+    entries: Arc<Mutex<Vec<String>>>,
+}
+
+impl TeardownLog {
+    /// Create an empty log.
+    pub fn new() -> Self {
+        TeardownLog::default()
+    }
+
+    /// Wrap `cleanup` so that it records `name` in the log when it runs.
+    pub fn record<F>(&self, name: &str, cleanup: F) -> impl FnOnce()
+    where
+        F: FnOnce(),
+    {
+        let log = self.clone();
+        let name = String::from(name);
+        move || {
+            log.lock().push(name);
+            cleanup()
+        }
+    }
+
+    /// Create a guard owning `v` that records `name` in the log and then
+    /// calls `dropfn`.
+    pub fn guard<T, F>(&self, name: &str, v: T, dropfn: F) -> ScopeGuard<T, impl FnOnce(T)>
+    where
+        F: FnOnce(T),
+    {
+        let log = self.clone();
+        let name = String::from(name);
+        guard(v, move |v| {
+            log.lock().push(name);
+            dropfn(v)
+        })
+    }
+
+    /// Return a copy of the recorded names, in execution order.
+    pub fn entries(&self) -> Vec<String> {
+        self.lock().clone()
+    }
+
+    /// Remove all recorded names.
+    pub fn clear(&self) {
+        self.lock().clear()
+    }
+
+    /// Return the position of the first record of `name`.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.lock().iter().position(|entry| entry == name)
+    }
+
+    /// Assert that a cleanup named `name` ran.
+    #[track_caller]
+    pub fn assert_ran(&self, name: &str) {
+        if self.position(name).is_none() {
+            panic!("{:?} did not run; log: {:?}", name, self.entries());
+        }
+    }
+
+    /// Assert that no cleanup named `name` ran.
+    #[track_caller]
+    pub fn assert_not_ran(&self, name: &str) {
+        if self.position(name).is_some() {
+            panic!("{:?} ran; log: {:?}", name, self.entries());
+        }
+    }
+
+    /// Assert that cleanups named `first` and `second` both ran, and that
+    /// `first` ran before `second`.
+    #[track_caller]
+    pub fn assert_ran_before(&self, first: &str, second: &str) {
+        match (self.position(first), self.position(second)) {
+            (Some(i), Some(j)) if i < j => {}
+            _ => panic!(
+                "expected {:?} to run before {:?}; log: {:?}",
+                first,
+                second,
+                self.entries()
+            ),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Vec<String>> {
+        // A panicking cleanup must not hide the log from the test.
+        self.entries
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl fmt::Debug for TeardownLog {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple(stringify!(TeardownLog))
+            .field(&*self.lock())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+    use teardown::Teardown;
+
+    #[test]
+    fn test_record_teardown() {
+        let log = TeardownLog::new();
+        {
+            let mut teardown = Teardown::new();
+            teardown.add("b", log.record("b", || {}));
+            teardown.add("a", log.record("a", || {}));
+        }
+        log.assert_ran_before("a", "b");
+        log.assert_not_ran("c");
+    }
+
+    #[test]
+    fn test_assert_ran_before_fails() {
+        let log = TeardownLog::new();
+        log.record("b", || {})();
+        log.record("a", || {})();
+        let result = catch_unwind(AssertUnwindSafe(|| log.assert_ran_before("a", "b")));
+        assert!(result.is_err());
+    }
+}
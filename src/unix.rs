@@ -0,0 +1,432 @@
+//! Guards for Unix processes and system resources.
+//!
+//! Requires crate feature `unix`, and a Unix target.
+
+use libc;
+use std::ffi::{CStr, CString};
+use std::io;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::ptr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Once;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use guard;
+use ScopeGuard;
+
+static FORK_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn after_fork_in_child() {
+    FORK_GENERATION.fetch_add(1, Ordering::Relaxed);
+}
+
+fn register_fork_handler() {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| unsafe {
+        let ret = libc::pthread_atfork(None, None, Some(after_fork_in_child));
+        assert_eq!(ret, 0, "pthread_atfork failed");
+    });
+}
+
+/// Return the number of `fork`s between the first fork-aware guard and the
+/// current process.
+///
+/// The count is zero in the process that created the first fork-aware guard,
+/// and increases by one in each forked child, grandchild and so on.
+pub fn fork_generation() -> usize {
+    register_fork_handler();
+    FORK_GENERATION.load(Ordering::Relaxed)
+}
+
+/// Create a new `ScopeGuard` owning `v` and with deferred closure `dropfn`,
+/// which is defused in forked child processes.
+///
+/// The closure only runs if the guard is dropped in the process that created
+/// it. In a child process created by `fork` the value is dropped without
+/// running the closure, so that for example the child does not delete the
+/// parent's temporary files.
+///
+/// Forks are detected with a handler registered with `pthread_atfork`, so
+/// a child created with a raw `fork` system call that bypasses the C library
+/// is not detected.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// fn main() {
+///     let _temp = scopeguard::unix::guard_fork_aware("/tmp/scratch", |path| {
+///         let _ = std::fs::remove_dir_all(path);
+///     });
+/// }
+/// ```
+pub fn guard_fork_aware<T, F>(v: T, dropfn: F) -> ScopeGuard<T, impl FnOnce(T)>
+where
+    F: FnOnce(T),
+{
+    let generation = fork_generation();
+    guard(v, move |v| {
+        if FORK_GENERATION.load(Ordering::Relaxed) == generation {
+            dropfn(v)
+        }
+    })
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+type Resource = libc::__rlimit_resource_t;
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+type Resource = libc::c_int;
+
+fn get_rlimit(resource: Resource) -> io::Result<libc::rlimit> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(resource, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit)
+}
+
+fn set_soft_rlimit(resource: Resource, soft: libc::rlim_t) -> io::Result<()> {
+    let mut limit = get_rlimit(resource)?;
+    limit.rlim_cur = soft;
+    if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set the soft limit of `resource` (for example `libc::RLIMIT_NOFILE`) to
+/// `soft`, and restore the previous soft limit at scope exit.
+///
+/// The hard limit is not changed, so `soft` can not exceed it. The guard
+/// holds the previous soft limit. Errors while restoring the limit are
+/// ignored.
+///
+/// ```
+/// extern crate libc;
+/// extern crate scopeguard;
+///
+/// fn main() -> std::io::Result<()> {
+///     let previous = scopeguard::unix::set_rlimit(libc::RLIMIT_NOFILE, 64)?;
+///     println!("open file limit was {}", *previous);
+///     Ok(())
+/// }
+/// ```
+pub fn set_rlimit(
+    resource: Resource,
+    soft: libc::rlim_t,
+) -> io::Result<ScopeGuard<libc::rlim_t, impl FnOnce(libc::rlim_t)>> {
+    let previous = get_rlimit(resource)?.rlim_cur;
+    set_soft_rlimit(resource, soft)?;
+    Ok(guard(previous, move |previous| {
+        let _ = set_soft_rlimit(resource, previous);
+    }))
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_affinity() -> io::Result<libc::cpu_set_t> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    let size = mem::size_of::<libc::cpu_set_t>();
+    if unsafe { libc::sched_getaffinity(0, size, &mut set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(set)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_affinity(set: &libc::cpu_set_t) -> io::Result<()> {
+    let size = mem::size_of::<libc::cpu_set_t>();
+    if unsafe { libc::sched_setaffinity(0, size, set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Pin the current thread to the CPUs in `cpus`, and restore the previous
+/// CPU affinity of the thread at scope exit.
+///
+/// The guard holds the previous CPU set. Errors while restoring the affinity
+/// are ignored.
+///
+/// Only available on Linux and Android.
+///
+/// # Panics
+///
+/// Panics if a CPU index is not less than `libc::CPU_SETSIZE`.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// fn main() -> std::io::Result<()> {
+///     let _pinned = scopeguard::unix::pin_to_cpus(&[0])?;
+///     // latency-critical section runs on CPU 0
+///     Ok(())
+/// }
+/// ```
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn pin_to_cpus(
+    cpus: &[usize],
+) -> io::Result<ScopeGuard<libc::cpu_set_t, impl FnOnce(libc::cpu_set_t)>> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+    for &cpu in cpus {
+        assert!(cpu < libc::CPU_SETSIZE as usize, "CPU index out of range");
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    let previous = get_affinity()?;
+    set_affinity(&set)?;
+    Ok(guard(previous, |previous| {
+        let _ = set_affinity(&previous);
+    }))
+}
+
+// Safety: as for `set_locale`.
+unsafe fn query_locale(category: libc::c_int) -> Option<CString> {
+    let current = unsafe { libc::setlocale(category, ptr::null()) };
+    if current.is_null() {
+        None
+    } else {
+        Some(unsafe { CStr::from_ptr(current) }.to_owned())
+    }
+}
+
+/// Set the C locale for `category` (for example `libc::LC_NUMERIC`) to
+/// `locale` with `setlocale`, and restore the previous locale at scope exit.
+///
+/// The guard holds the name of the previous locale.
+///
+/// # Safety
+///
+/// The locale is process-wide state, and `setlocale` is not thread-safe. No
+/// other thread may call `setlocale`, or any function that reads the locale,
+/// such as `printf` or `strtod`, from when this function is called until the
+/// guard has been dropped. The easiest way to ensure this is to only call it
+/// while the process is single-threaded.
+///
+/// ```
+/// extern crate libc;
+/// extern crate scopeguard;
+///
+/// use std::ffi::CStr;
+///
+/// fn main() -> std::io::Result<()> {
+///     let c = CStr::from_bytes_with_nul(b"C\0").unwrap();
+///     // The program has no other threads.
+///     let _locale = unsafe { scopeguard::unix::set_locale(libc::LC_NUMERIC, c)? };
+///     // call into a C library that requires the "C" numeric locale
+///     Ok(())
+/// }
+/// ```
+pub unsafe fn set_locale(
+    category: libc::c_int,
+    locale: &CStr,
+) -> io::Result<ScopeGuard<CString, impl FnOnce(CString)>> {
+    let previous = query_locale(category)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid locale category"))?;
+    if unsafe { libc::setlocale(category, locale.as_ptr()) }.is_null() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "locale is not available",
+        ));
+    }
+    Ok(guard(previous, move |previous| unsafe {
+        libc::setlocale(category, previous.as_ptr());
+    }))
+}
+
+/// Create a guard that flushes the memory-mapped `region` with `msync` at
+/// scope exit, including during unwinding.
+///
+/// `flags` are passed to `msync`, for example `libc::MS_SYNC` to wait until
+/// the data is written, or `libc::MS_ASYNC`. If `msync` fails, the error is
+/// passed to `on_error`.
+///
+/// The guard derefs to `region`, so writes through it are flushed even if
+/// the writer panics. `region` must start at a page boundary, as mappings
+/// do.
+///
+/// ```no_run
+/// extern crate libc;
+/// extern crate scopeguard;
+///
+/// fn write_record(mapping: &mut [u8]) {
+///     let mut region = scopeguard::unix::msync_on_drop(mapping, libc::MS_SYNC, |err| {
+///         eprintln!("msync failed: {}", err);
+///     });
+///     region[..4].copy_from_slice(b"DONE");
+/// }
+/// # fn main() {}
+/// ```
+pub fn msync_on_drop<'a, E>(
+    region: &'a mut [u8],
+    flags: libc::c_int,
+    on_error: E,
+) -> ScopeGuard<&'a mut [u8], impl FnOnce(&'a mut [u8])>
+where
+    E: FnOnce(io::Error),
+{
+    guard(region, move |region| {
+        let addr = region.as_mut_ptr() as *mut libc::c_void;
+        if unsafe { libc::msync(addr, region.len(), flags) } != 0 {
+            on_error(io::Error::last_os_error());
+        }
+    })
+}
+
+/// Spawn `command` as the leader of a new process group, and terminate the
+/// whole group at scope exit, including during unwinding.
+///
+/// At scope exit, `SIGTERM` is sent to the process group. Processes still in
+/// the group after `grace` are sent `SIGKILL`. Then the child is reaped.
+/// Signalling the group instead of only the child also terminates the
+/// processes it spawned, unless they moved to another process group.
+///
+/// The guard derefs to the `Child`.
+///
+/// ```no_run
+/// extern crate scopeguard;
+///
+/// use std::process::Command;
+/// use std::time::Duration;
+///
+/// fn main() -> std::io::Result<()> {
+///     let mut server = Command::new("./test-server");
+///     let server = scopeguard::unix::spawn_process_group(&mut server, Duration::from_secs(5))?;
+///     println!("server running as {}", server.id());
+///     Ok(())
+/// }
+/// ```
+pub fn spawn_process_group(
+    command: &mut Command,
+    grace: Duration,
+) -> io::Result<ScopeGuard<Child, impl FnOnce(Child)>> {
+    unsafe {
+        command.pre_exec(|| {
+            if libc::setpgid(0, 0) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    let child = command.spawn()?;
+    Ok(guard(child, move |mut child| {
+        let group = -(child.id() as libc::pid_t);
+        let deadline = Instant::now() + grace;
+        unsafe {
+            libc::kill(group, libc::SIGTERM);
+        }
+        loop {
+            // Reap the leader as soon as it exits, so that it doesn't keep
+            // the group alive as a zombie.
+            let _ = child.try_wait();
+            if unsafe { libc::kill(group, 0) } != 0 {
+                break;
+            }
+            if Instant::now() >= deadline {
+                unsafe {
+                    libc::kill(group, libc::SIGKILL);
+                }
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let _ = child.wait();
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_set_rlimit_restores() {
+        let before = get_rlimit(libc::RLIMIT_NOFILE).unwrap();
+        let lowered = before.rlim_cur - 1;
+        {
+            let previous = set_rlimit(libc::RLIMIT_NOFILE, lowered).unwrap();
+            assert_eq!(*previous, before.rlim_cur);
+            assert_eq!(get_rlimit(libc::RLIMIT_NOFILE).unwrap().rlim_cur, lowered);
+        }
+        assert_eq!(
+            get_rlimit(libc::RLIMIT_NOFILE).unwrap().rlim_cur,
+            before.rlim_cur
+        );
+    }
+
+    #[test]
+    fn test_msync_on_drop() {
+        let len = 4096;
+        unsafe {
+            let addr = libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            assert_ne!(addr, libc::MAP_FAILED);
+            let mapping = std::slice::from_raw_parts_mut(addr as *mut u8, len);
+            let mut failed = None;
+            {
+                let mut region = msync_on_drop(mapping, libc::MS_SYNC, |e| failed = Some(e));
+                region[0] = 1;
+            }
+            assert!(failed.is_none());
+            // An unaligned region is rejected by msync.
+            {
+                let _region = msync_on_drop(&mut mapping[1..], libc::MS_SYNC, |e| failed = Some(e));
+            }
+            assert!(failed.is_some());
+            libc::munmap(addr, len);
+        }
+    }
+
+    #[test]
+    fn test_spawn_process_group_kills_grandchildren() {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg("trap '' TERM; sleep 100 & sleep 100");
+        let group = {
+            let child = spawn_process_group(&mut command, Duration::from_millis(100)).unwrap();
+            child.id() as libc::pid_t
+        };
+        // Killed orphans are reaped by init asynchronously.
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while unsafe { libc::kill(-group, 0) } == 0 {
+            assert!(Instant::now() < deadline, "process group still alive");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_guard_defused_in_child() {
+        let generation = fork_generation();
+        let guard = guard_fork_aware((), |()| unsafe { libc::_exit(2) });
+        unsafe {
+            match libc::fork() {
+                -1 => panic!("fork failed"),
+                0 => {
+                    // Child: the guard must not run.
+                    let code = if fork_generation() == generation + 1 {
+                        drop(guard);
+                        0
+                    } else {
+                        1
+                    };
+                    libc::_exit(code);
+                }
+                child => {
+                    let mut status = 0;
+                    assert_eq!(libc::waitpid(child, &mut status, 0), child);
+                    assert!(libc::WIFEXITED(status));
+                    assert_eq!(libc::WEXITSTATUS(status), 0);
+                    ScopeGuard::into_inner(guard);
+                }
+            }
+        }
+    }
+}
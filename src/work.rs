@@ -0,0 +1,174 @@
+//! Panic-safe consumption of batches of work items.
+//!
+//! Requires crate feature `use_std`.
+
+use std::fmt;
+use std::mem;
+use std::vec;
+
+/// A batch of work items that returns its unprocessed items to a sink unless
+/// it is finished.
+///
+/// Iterate the batch by reference, `for item in &mut batch`, to take items
+/// for processing. When all the work is done, call
+/// [`finish`](#method.finish): the items taken are then done. If the batch is
+/// dropped without `finish` — after a panic, a `break` out of the loop or an
+/// early return with `?` — each item that was not taken yet is passed to the
+/// `requeue` closure, for example to push it back into a queue or channel.
+///
+/// The item being processed when the batch is abandoned is owned by the
+/// consumer and is not returned.
+///
+/// ```
+/// extern crate scopeguard;
+///
+/// use std::sync::mpsc::channel;
+/// use scopeguard::work::WorkBatch;
+///
+/// fn process(batch: &mut WorkBatch<u32, impl FnMut(u32)>) -> Result<(), String> {
+///     for item in batch {
+///         if item == 2 {
+///             return Err(format!("failed to process {}", item));
+///         }
+///     }
+///     Ok(())
+/// }
+///
+/// fn main() {
+///     let (tx, rx) = channel();
+///     let mut batch = WorkBatch::new(vec![1, 2, 3], |item| tx.send(item).unwrap());
+///     match process(&mut batch) {
+///         Ok(()) => drop(batch.finish()),
+///         // Dropping the batch returns item 3 to the queue.
+///         Err(_) => drop(batch),
+///     }
+///     assert_eq!(rx.try_iter().collect::<Vec<_>>(), [3]);
+/// }
+/// ```
+pub struct WorkBatch<T, R>
+where
+    R: FnMut(T),
+{
+    items: vec::IntoIter<T>,
+    requeue: R,
+    processed: usize,
+}
+
+impl<T, R> WorkBatch<T, R>
+where
+    R: FnMut(T),
+{
+    /// Create a batch owning `items`, which returns unprocessed items
+    /// through `requeue` when dropped without [`finish`](#method.finish).
+    #[must_use]
+    pub fn new<I>(items: I, requeue: R) -> Self
+    where
+        I: IntoIterator<Item = T>,
+    {
+        WorkBatch {
+            items: items.into_iter().collect::<Vec<_>>().into_iter(),
+            requeue,
+            processed: 0,
+        }
+    }
+
+    /// Return the number of items taken from the batch so far.
+    pub fn processed(&self) -> usize {
+        self.processed
+    }
+
+    /// Return the items that have not been taken yet.
+    pub fn remaining(&self) -> &[T] {
+        self.items.as_slice()
+    }
+
+    /// Complete the batch: the items taken so far are done.
+    ///
+    /// Return the items that were not taken, without passing them to
+    /// `requeue`.
+    pub fn finish(mut self) -> Vec<T> {
+        mem::replace(&mut self.items, Vec::new().into_iter()).collect()
+    }
+}
+
+impl<T, R> Iterator for WorkBatch<T, R>
+where
+    R: FnMut(T),
+{
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        let item = self.items.next();
+        if item.is_some() {
+            self.processed += 1;
+        }
+        item
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.items.size_hint()
+    }
+}
+
+impl<T, R> Drop for WorkBatch<T, R>
+where
+    R: FnMut(T),
+{
+    fn drop(&mut self) {
+        for item in &mut self.items {
+            (self.requeue)(item);
+        }
+    }
+}
+
+impl<T, R> fmt::Debug for WorkBatch<T, R>
+where
+    T: fmt::Debug,
+    R: FnMut(T),
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct(stringify!(WorkBatch))
+            .field("remaining", &self.remaining())
+            .field("processed", &self.processed)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::{catch_unwind, AssertUnwindSafe};
+
+    #[test]
+    fn test_requeue_remainder() {
+        let mut queue = Vec::new();
+        let _ = catch_unwind(AssertUnwindSafe(|| {
+            let mut batch = WorkBatch::new(vec![1, 2, 3, 4], |item| queue.push(item));
+            assert_eq!(batch.next(), Some(1));
+            assert_eq!(batch.processed(), 1);
+            assert_eq!(batch.remaining(), &[2, 3, 4]);
+            panic!("failed to process 1");
+        }));
+        assert_eq!(queue, [2, 3, 4]);
+    }
+
+    #[test]
+    fn test_requeue_after_break() {
+        let mut queue = Vec::new();
+        for item in WorkBatch::new(vec![1, 2, 3], |item| queue.push(item)) {
+            if item == 2 {
+                break;
+            }
+        }
+        assert_eq!(queue, [3]);
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut queue = Vec::new();
+        let mut batch = WorkBatch::new(vec![1, 2, 3], |item| queue.push(item));
+        assert_eq!(batch.next(), Some(1));
+        assert_eq!(batch.finish(), [2, 3]);
+        assert!(queue.is_empty());
+    }
+}
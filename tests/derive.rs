@@ -0,0 +1,80 @@
+#![cfg(feature = "derive")]
+
+#[macro_use]
+extern crate scopeguard;
+
+use std::cell::RefCell;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+
+#[derive(Finalize)]
+struct Transaction<'a> {
+    #[finalize(|log: &mut &RefCell<Vec<&str>>| log.borrow_mut().push("release"))]
+    conn: &'a RefCell<Vec<&'static str>>,
+    #[finalize(on_unwind, |log: &mut &RefCell<Vec<&str>>| log.borrow_mut().push("rollback"))]
+    journal: &'a RefCell<Vec<&'static str>>,
+    #[finalize(on_success, |log: &mut &RefCell<Vec<&str>>| log.borrow_mut().push("commit"))]
+    commit: &'a RefCell<Vec<&'static str>>,
+}
+
+#[derive(Finalize)]
+struct Counter(#[finalize(|n| *n += 1)] u32);
+
+#[test]
+fn reverse_field_order() {
+    let log = RefCell::new(Vec::new());
+    drop(Transaction {
+        conn: &log,
+        journal: &log,
+        commit: &log,
+    });
+    assert_eq!(*log.borrow(), ["commit", "release"]);
+}
+
+#[test]
+fn on_unwind() {
+    let log = RefCell::new(Vec::new());
+    let _ = catch_unwind(AssertUnwindSafe(|| {
+        let _tx = Transaction {
+            conn: &log,
+            journal: &log,
+            commit: &log,
+        };
+        panic!("failed");
+    }));
+    assert_eq!(*log.borrow(), ["rollback", "release"]);
+}
+
+#[test]
+fn tuple_struct() {
+    drop(Counter(0));
+}
+
+thread_local!(static EXITS: RefCell<Vec<&'static str>> = const { RefCell::new(Vec::new()) });
+
+fn record(event: &'static str) {
+    EXITS.with(|exits| exits.borrow_mut().push(event));
+}
+
+#[scopeguard::deferred(record("exit"))]
+#[scopeguard::deferred(on_unwind, record("unwind"))]
+fn parse(input: &str, fail: bool) -> Result<u32, std::num::ParseIntError> {
+    if fail {
+        panic!("failed");
+    }
+    let n = input.parse()?;
+    record("parsed");
+    Ok(n)
+}
+
+#[test]
+fn deferred_attribute() {
+    assert_eq!(parse("1", false), Ok(1));
+    assert!(parse("x", false).is_err());
+    let _ = catch_unwind(|| parse("1", true));
+    EXITS.with(|exits| {
+        assert_eq!(
+            *exits.borrow(),
+            ["parsed", "exit", "exit", "exit", "unwind"]
+        )
+    });
+}
@@ -0,0 +1,158 @@
+//! Check that the macros expand correctly in unusual contexts.
+
+#![no_implicit_prelude]
+
+#[macro_use]
+extern crate scopeguard;
+
+use std::cell::Cell;
+
+macro_rules! defer_twice {
+    ($counter:expr) => {
+        defer!($counter.set($counter.get() + 1));
+        defer!($counter.set($counter.get() + 1));
+    };
+}
+
+#[no_implicit_prelude]
+mod inner {
+    use std::cell::Cell;
+
+    pub fn run(counter: &Cell<u32>) {
+        defer! {
+            counter.set(counter.get() + 1);
+        }
+        defer_on_success! {
+            counter.set(counter.get() + 1);
+        }
+        defer_on_unwind! {
+            counter.set(counter.get() + 100);
+        }
+    }
+}
+
+#[test]
+fn no_implicit_prelude() {
+    let counter = Cell::new(0);
+    inner::run(&counter);
+    ::std::assert_eq!(counter.get(), 2);
+}
+
+#[test]
+fn macro_generated() {
+    let counter = Cell::new(0);
+    {
+        defer_twice!(counter);
+    }
+    ::std::assert_eq!(counter.get(), 2);
+}
+
+#[test]
+fn defer_try_catch() {
+    let caught = Cell::new(0);
+    {
+        defer!(try {
+            "x".parse::<u32>()?;
+        } catch |_e: ::std::num::ParseIntError| caught.set(caught.get() + 1));
+    }
+    ::std::assert_eq!(caught.get(), 1);
+}
+
+#[test]
+fn guards_macro() {
+    let counter = Cell::new(0);
+    {
+        guards! {
+            _a = ((), |()| counter.set(counter.get() + 1));
+            _b = ((), |()| counter.set(counter.get() + 1));
+        }
+    }
+    ::std::assert_eq!(counter.get(), 2);
+}
+
+#[test]
+fn defer_named() {
+    let counter = Cell::new(0);
+    {
+        defer!(cleanup: {
+            counter.set(counter.get() + 1);
+        });
+        ::scopeguard::ScopeGuard::cancel(cleanup);
+    }
+    ::std::assert_eq!(counter.get(), 0);
+}
+
+#[test]
+fn defer_move() {
+    let counter = Cell::new(0);
+    {
+        let step = 2;
+        defer!(move(step) {
+            counter.set(counter.get() + step);
+        });
+    }
+    ::std::assert_eq!(counter.get(), 2);
+}
+
+#[test]
+fn scope_macro() {
+    let counter = Cell::new(0);
+    scope! {
+        defer { counter.set(counter.get() * 2); }
+        defer { counter.set(counter.get() + 1); }
+    }
+    ::std::assert_eq!(counter.get(), 2);
+}
+
+#[test]
+fn defer_value() {
+    let counter = Cell::new(0);
+    {
+        let step = 3;
+        defer!(step => |s| counter.set(counter.get() + s));
+    }
+    ::std::assert_eq!(counter.get(), 3);
+}
+
+#[test]
+fn guard_macro() {
+    let counter = Cell::new(0);
+    {
+        let _a = guard!(on_success, 1, |n| counter.set(counter.get() + n));
+        let _b = guard!(on_unwind, 10, |n| counter.set(counter.get() + n));
+    }
+    ::std::assert_eq!(counter.get(), 1);
+}
+
+#[test]
+fn finally_macro() {
+    let counter = Cell::new(0);
+    let value = finally!({ counter.get() + 1 } cleanup { counter.set(10); });
+    ::std::assert_eq!((value, counter.get()), (1, 10));
+}
+
+#[cfg(feature = "use_std")]
+#[test]
+fn try_defer_macro() {
+    let counter = Cell::new(0);
+    {
+        try_defer!(ignore, {
+            counter.set(counter.get() + 1);
+            "x".parse::<u32>()?;
+            counter.set(counter.get() + 1);
+        });
+    }
+    ::std::assert_eq!(counter.get(), 1);
+}
+
+#[test]
+fn cancel_defer_macro() {
+    let counter = Cell::new(0);
+    {
+        defer!(cleanup: {
+            counter.set(counter.get() + 1);
+        });
+        cancel_defer!(cleanup);
+    }
+    ::std::assert_eq!(counter.get(), 0);
+}
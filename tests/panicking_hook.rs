@@ -0,0 +1,26 @@
+//! The panicking hook is global, so it is tested in its own test binary.
+
+extern crate scopeguard;
+
+use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use scopeguard::{guard_on_success, guard_on_unwind, reset_panicking_hook, set_panicking_hook};
+
+static FAKE_PANIC: AtomicBool = AtomicBool::new(false);
+
+#[test]
+fn panicking_hook() {
+    let runs = Cell::new(0);
+    set_panicking_hook(|| FAKE_PANIC.load(Ordering::SeqCst));
+    FAKE_PANIC.store(true, Ordering::SeqCst);
+    drop(guard_on_unwind((), |()| runs.set(runs.get() + 1)));
+    drop(guard_on_success((), |()| runs.set(runs.get() + 10)));
+    assert_eq!(runs.get(), 1);
+
+    reset_panicking_hook();
+    drop(guard_on_unwind((), |()| runs.set(runs.get() + 100)));
+    drop(guard_on_success((), |()| runs.set(runs.get() + 1000)));
+    assert_eq!(runs.get(), 1001);
+    FAKE_PANIC.store(false, Ordering::SeqCst);
+}